@@ -0,0 +1,61 @@
+//! The CPUs events can be counted on, as the kernel
+//! lists them in sysfs, e.g. `0-3,8` for CPUs 0, 1, 2, 3 and 8.
+
+use std::fs;
+use std::io;
+
+/// Where the kernel lists the CPUs that are online.
+const ONLINE: &str = "/sys/devices/system/cpu/online";
+
+/// The largest `CONFIG_NR_CPUS` a kernel can be
+/// built with; every CPU is numbered below it.
+const MAX_CPUS: i32 = 8192;
+
+/// Every online CPU, lowest first.
+pub fn online_cpus() -> io::Result<Vec<i32>> {
+    let list = fs::read_to_string(ONLINE)?;
+    parse_cpu_list(&list).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad CPU list in {}", ONLINE),
+        )
+    })
+}
+
+/// Parse a CPU list like `0-3,8`, as used by sysfs
+/// and `-C`. CPUs are returned sorted, without duplicates.
+/// Lists naming CPUs no kernel could have are refused, rather
+/// than making a huge list of them.
+pub fn parse_cpu_list(list: &str) -> Option<Vec<i32>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',') {
+        let (first, last): (i32, i32) = match range.split_once('-') {
+            Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+            None => {
+                let cpu = range.parse().ok()?;
+                (cpu, cpu)
+            }
+        };
+        if first > last || last >= MAX_CPUS {
+            return None;
+        }
+        cpus.extend(first..=last);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Some(cpus)
+}
+
+#[cfg(test)]
+#[test]
+fn parse_cpu_list_test() {
+    assert_eq!(parse_cpu_list("0-3,8\n"), Some(vec![0, 1, 2, 3, 8]));
+    assert_eq!(parse_cpu_list("5,1-2,2"), Some(vec![1, 2, 5]));
+    assert_eq!(parse_cpu_list("0"), Some(vec![0]));
+    assert_eq!(parse_cpu_list("3-1"), None);
+    assert_eq!(parse_cpu_list("1,,2"), None);
+    assert_eq!(parse_cpu_list("-1"), None);
+    assert_eq!(parse_cpu_list("0-2147483647"), None);
+    assert_eq!(parse_cpu_list("8191").map(|cpus| cpus.len()), Some(1));
+    assert!(!online_cpus().unwrap().is_empty());
+}
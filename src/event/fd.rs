@@ -12,36 +12,186 @@ use crate::event::sys::linux::*;
 use crate::event::sys::wrapper::*;
 use crate::event::utils::*;
 use libc::{c_int, c_ulong, pid_t, syscall, SYS_perf_event_open};
+use std::ffi::CString;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
 
-/// Stores a raw file descriptor
+/// Owns a raw file descriptor
 /// for use in various `perf_event_open()`
-/// system call wrappers.
+/// system call wrappers, along with the
+/// `read_format` and `sample_type` it was opened with.
+/// The descriptor is closed when the `FileDesc` is dropped.
 #[derive(Debug)]
-pub struct FileDesc(i32);
+pub struct FileDesc {
+    fd: i32,
+    read_format: u64,
+    sample_type: u64,
+}
+
+/// A single counter value, tagged with the ID the
+/// kernel assigned to the event. `id` is 0 unless
+/// `PERF_FORMAT_ID` was requested. Match `id` against
+/// `FileDesc::id()` to find its event in a group read.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CounterValue {
+    pub value: u64,
+    pub id: u64,
+}
+
+/// A structured counter reading, decoded according to the
+/// `read_format` of the event. `values` holds one entry for a
+/// single event, or one entry per member for a group read.
+/// `time_enabled` and `time_running` are in nanoseconds, and
+/// are 0 unless `PERF_FORMAT_TOTAL_TIME_*` was requested.
+#[derive(Debug, Clone, Default)]
+pub struct Reading {
+    pub time_enabled: u64,
+    pub time_running: u64,
+    pub values: Vec<CounterValue>,
+}
+
+impl Reading {
+    /// Decode a reading laid out according to `read_format`, as
+    /// returned by `read()` or embedded in a `PERF_SAMPLE_READ` sample.
+    /// For a single event the layout is
+    /// `{ value, [time_enabled], [time_running], [id] }`, and for a
+    /// group it is `{ nr, [time_enabled], [time_running], { value, [id] } * nr }`.
+    pub fn decode(read_format: u64, words: &mut impl Iterator<Item = u64>) -> Reading {
+        let has = |flag: u32| read_format & flag as u64 != 0;
+        let mut next = || words.next().unwrap_or(0);
+        let mut reading = Reading::default();
+        if has(perf_event_read_format_PERF_FORMAT_GROUP) {
+            let nr = next();
+            if has(perf_event_read_format_PERF_FORMAT_TOTAL_TIME_ENABLED) {
+                reading.time_enabled = next();
+            }
+            if has(perf_event_read_format_PERF_FORMAT_TOTAL_TIME_RUNNING) {
+                reading.time_running = next();
+            }
+            for _ in 0..nr {
+                let value = next();
+                let id = if has(perf_event_read_format_PERF_FORMAT_ID) {
+                    next()
+                } else {
+                    0
+                };
+                reading.values.push(CounterValue { value, id });
+            }
+        } else {
+            let value = next();
+            if has(perf_event_read_format_PERF_FORMAT_TOTAL_TIME_ENABLED) {
+                reading.time_enabled = next();
+            }
+            if has(perf_event_read_format_PERF_FORMAT_TOTAL_TIME_RUNNING) {
+                reading.time_running = next();
+            }
+            let id = if has(perf_event_read_format_PERF_FORMAT_ID) {
+                next()
+            } else {
+                0
+            };
+            reading.values.push(CounterValue { value, id });
+        }
+        reading
+    }
+    /// The raw count of the first (or only) counter.
+    pub fn value(&self) -> u64 {
+        self.values.first().map_or(0, |v| v.value)
+    }
+    /// The counts accumulated between `earlier` and this reading.
+    /// Both readings must come from the same event or group. Counts
+    /// taken away that weren't in this one leave 0, not a wrap.
+    pub fn since(&self, earlier: &Reading) -> Reading {
+        let values = self
+            .values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| CounterValue {
+                value: v
+                    .value
+                    .saturating_sub(earlier.values.get(i).map_or(0, |e| e.value)),
+                id: v.id,
+            })
+            .collect();
+        Reading {
+            time_enabled: self.time_enabled.saturating_sub(earlier.time_enabled),
+            time_running: self.time_running.saturating_sub(earlier.time_running),
+            values,
+        }
+    }
+    /// Add `other` into this reading, to total the same events
+    /// counted separately, e.g. on each thread of a process.
+    /// Like perf, counts are scaled only once they are totalled.
+    pub fn add(&mut self, other: &Reading) {
+        if self.values.len() < other.values.len() {
+            self.values
+                .resize(other.values.len(), CounterValue::default());
+        }
+        for (total, v) in self.values.iter_mut().zip(&other.values) {
+            total.value += v.value;
+        }
+        self.time_enabled += other.time_enabled;
+        self.time_running += other.time_running;
+    }
+    /// Fraction of the enabled time the counters were
+    /// actually scheduled on the PMU. Less than 1.0 when
+    /// the kernel had to multiplex events.
+    pub fn running_ratio(&self) -> f64 {
+        if self.time_enabled == 0 {
+            return 1.0;
+        }
+        self.time_running as f64 / self.time_enabled as f64
+    }
+    /// Counter `i` extrapolated over the whole enabled time,
+    /// as perf does for multiplexed events. Returns `None`
+    /// if the counter never ran.
+    pub fn scaled(&self, i: usize) -> Option<u64> {
+        let value = self.values.get(i)?.value;
+        if self.time_running == 0 {
+            return None;
+        }
+        if self.time_enabled == self.time_running {
+            return Some(value);
+        }
+        let scaled = value as u128 * self.time_enabled as u128 / self.time_running as u128;
+        Some(scaled as u64)
+    }
+}
 
 impl FileDesc {
     /// Set up performance monitoring for
     /// configured event without any flags.
-    /// Panics if `perf_event_open()` fails.
-    pub fn new(event: &mut perf_event_attr, pid: Option<i32>, cpu: i32, group_fd: i32) -> Self {
+    /// On failure the error carries the `errno` and
+    /// the attributes `perf_event_open()` was called with.
+    pub fn new(
+        event: &mut perf_event_attr,
+        pid: Option<i32>,
+        cpu: i32,
+        group_fd: i32,
+    ) -> Result<Self, SysErr> {
         let ret: i32;
         let pid = match pid {
             Some(x) => x as pid_t,
             None => 0_i32,
         };
-        ret = perf_event_open(event, pid as pid_t, cpu, group_fd, 0) as i32;
+        // Don't leak counters into programs we exec.
+        let flags = PERF_FLAG_FD_CLOEXEC as usize;
+        ret = perf_event_open(event, pid as pid_t, cpu, group_fd, flags) as i32;
         if ret == -1 {
-            panic!("Panic: system call perf_event_open() failed in FileDesc::new()");
+            return Err(SysErr::open(event));
         }
-        Self(ret)
+        Ok(Self {
+            fd: ret,
+            read_format: event.read_format,
+            sample_type: event.sample_type,
+        })
     }
     /// Enable the performance counter
     /// associated with `fd`.
     pub fn enable(&self) -> Result<(), SysErr> {
         let ret: i32;
-        ret = unsafe { libc::ioctl(self.0, ENABLE as u64, 0) };
+        ret = unsafe { libc::ioctl(self.fd, ENABLE as u64, 0) };
         if ret == -1 {
-            return Err(SysErr::IoFail);
+            return Err(SysErr::IoFail(errno()));
         }
         Ok(())
     }
@@ -49,9 +199,57 @@ impl FileDesc {
     /// associated with `fd`.
     pub fn disable(&self) -> Result<(), SysErr> {
         let ret: i32;
-        ret = unsafe { libc::ioctl(self.0, DISABLE as u64, 0) };
+        ret = unsafe { libc::ioctl(self.fd, DISABLE as u64, 0) };
+        if ret == -1 {
+            return Err(SysErr::IoFail(errno()));
+        }
+        Ok(())
+    }
+    /// Enable every counter in the group
+    /// led by the event associated with `fd`.
+    pub fn enable_group(&self) -> Result<(), SysErr> {
+        let ret: i32;
+        ret = unsafe {
+            libc::ioctl(
+                self.fd,
+                ENABLE as u64,
+                perf_event_ioc_flags_PERF_IOC_FLAG_GROUP,
+            )
+        };
+        if ret == -1 {
+            return Err(SysErr::IoFail(errno()));
+        }
+        Ok(())
+    }
+    /// Disable every counter in the group
+    /// led by the event associated with `fd`.
+    pub fn disable_group(&self) -> Result<(), SysErr> {
+        let ret: i32;
+        ret = unsafe {
+            libc::ioctl(
+                self.fd,
+                DISABLE as u64,
+                perf_event_ioc_flags_PERF_IOC_FLAG_GROUP,
+            )
+        };
+        if ret == -1 {
+            return Err(SysErr::IoFail(errno()));
+        }
+        Ok(())
+    }
+    /// Reset every counter in the group
+    /// led by the event associated with `fd` to 0.
+    pub fn reset_group(&self) -> Result<(), SysErr> {
+        let ret: i32;
+        ret = unsafe {
+            libc::ioctl(
+                self.fd,
+                RESET as u64,
+                perf_event_ioc_flags_PERF_IOC_FLAG_GROUP,
+            )
+        };
         if ret == -1 {
-            return Err(SysErr::IoFail);
+            return Err(SysErr::IoFail(errno()));
         }
         Ok(())
     }
@@ -69,18 +267,18 @@ impl FileDesc {
             return Err(SysErr::IoArg);
         }
         let arg: *const usize = &count;
-        ret = unsafe { libc::ioctl(self.0, REFRESH as u64, arg) };
+        ret = unsafe { libc::ioctl(self.fd, REFRESH as u64, arg) };
         if ret == -1 {
-            return Err(SysErr::IoFail);
+            return Err(SysErr::IoFail(errno()));
         }
         Ok(())
     }
     /// Reset the performance counter to 0.
     pub fn reset(&self) -> Result<(), SysErr> {
         let ret: i32;
-        ret = unsafe { libc::ioctl(self.0, RESET as u64, 0) };
+        ret = unsafe { libc::ioctl(self.fd, RESET as u64, 0) };
         if ret == -1 {
-            return Err(SysErr::IoFail);
+            return Err(SysErr::IoFail(errno()));
         }
         Ok(())
     }
@@ -91,21 +289,33 @@ impl FileDesc {
     pub fn overflow_period(&self, interval: usize) -> Result<(), SysErr> {
         let ret: i32;
         let arg: *const usize = &interval;
-        ret = unsafe { libc::ioctl(self.0, PERIOD as u64, arg) };
+        ret = unsafe { libc::ioctl(self.fd, PERIOD as u64, arg) };
         if ret == -1 {
-            return Err(SysErr::IoFail);
+            return Err(SysErr::IoFail(errno()));
         }
         Ok(())
     }
-    /// Report counter information to
-    /// specific file descriptor.
-    pub fn set_output(&self) -> Result<(), SysErr> {
-        todo!()
+    /// Report counter information to the
+    /// ring buffer of the event associated with `target`
+    /// instead of our own. Both events must be on the
+    /// same CPU, or both must follow the same task.
+    pub fn set_output(&self, target: &FileDesc) -> Result<(), SysErr> {
+        let ret: i32;
+        ret = unsafe { libc::ioctl(self.fd, SET_OUTPUT as u64, target.fd) };
+        if ret == -1 {
+            return Err(SysErr::IoFail(errno()));
+        }
+        Ok(())
     }
     /// Ignore counter output for event
-    /// associated with `fd`.
+    /// associated with `fd`, undoing `set_output()`.
     pub fn ignore_output(&self) -> Result<(), SysErr> {
-        todo!()
+        let ret: i32;
+        ret = unsafe { libc::ioctl(self.fd, SET_OUTPUT as u64, -1_i64 as c_ulong) };
+        if ret == -1 {
+            return Err(SysErr::IoFail(errno()));
+        }
+        Ok(())
     }
     /// Return event ID value
     /// associated with `fd`.
@@ -114,8 +324,8 @@ impl FileDesc {
         let mut ret: usize = 0;
         ret = unsafe {
             let result: *mut usize = &mut ret;
-            if libc::ioctl(self.0, ID as u64, result) == -1 {
-                return Err(SysErr::IoFail);
+            if libc::ioctl(self.fd, ID as u64, result) == -1 {
+                return Err(SysErr::IoFail(errno()));
             }
             *result
         };
@@ -126,27 +336,148 @@ impl FileDesc {
     }
     /// Pause writing to ring-buffer
     /// for associated file descriptor.
+    /// The ring-buffer must already be mapped.
     pub fn pause_output(&self) -> Result<(), SysErr> {
-        todo!()
+        let ret: i32;
+        ret = unsafe { libc::ioctl(self.fd, PAUSE_OUTPUT as u64, 1 as c_ulong) };
+        if ret == -1 {
+            return Err(SysErr::IoFail(errno()));
+        }
+        Ok(())
     }
     /// Resume writing to ring-buffer
     /// for associated file descriptor.
     pub fn resume_output(&self) -> Result<(), SysErr> {
-        todo!()
+        let ret: i32;
+        ret = unsafe { libc::ioctl(self.fd, PAUSE_OUTPUT as u64, 0 as c_ulong) };
+        if ret == -1 {
+            return Err(SysErr::IoFail(errno()));
+        }
+        Ok(())
     }
     /// Modify the attributes for
-    /// a specified event.
-    pub fn modify_attributes(&self, _event: *const perf_event_attr) -> Result<(), SysErr> {
-        todo!()
+    /// a specified event. The kernel only supports this
+    /// for breakpoint events, where it can move or resize
+    /// the breakpoint without closing and reopening it.
+    pub fn modify_attributes(&self, event: &perf_event_attr) -> Result<(), SysErr> {
+        let ret: i32;
+        let arg: *const perf_event_attr = event;
+        ret = unsafe { libc::ioctl(self.fd, MODIFY_ATTRIBUTES as u64, arg) };
+        if ret == -1 {
+            return Err(SysErr::IoFail(errno()));
+        }
+        Ok(())
     }
-    /// Read counter value associated
-    /// with field of `FileDesc` caller.
-    pub fn read(&self) -> Result<isize, SysErr> {
-        let ret = read_wrap(self.0);
+    /// Set a tracepoint filter, such as `"prev_pid == 1"`,
+    /// on the tracepoint event associated with `fd`.
+    /// See the kernel's `trace/events.rst` for the syntax.
+    pub fn set_filter(&self, filter: &str) -> Result<(), SysErr> {
+        let ret: i32;
+        let filter = match CString::new(filter) {
+            Ok(filter) => filter,
+            Err(_) => return Err(SysErr::IoArg),
+        };
+        ret = unsafe { libc::ioctl(self.fd, SET_FILTER as u64, filter.as_ptr()) };
         if ret == -1 {
-            return Err(SysErr::ReadFail);
+            return Err(SysErr::IoFail(errno()));
+        }
+        Ok(())
+    }
+    /// Attach the loaded BPF program `prog_fd`
+    /// to the tracepoint or kprobe event associated with `fd`.
+    pub fn set_bpf(&self, prog_fd: RawFd) -> Result<(), SysErr> {
+        let ret: i32;
+        ret = unsafe { libc::ioctl(self.fd, SET_BPF as u64, prog_fd as c_ulong) };
+        if ret == -1 {
+            return Err(SysErr::IoFail(errno()));
+        }
+        Ok(())
+    }
+    /// Return the IDs of the BPF programs attached
+    /// to the tracepoint or kprobe event associated with `fd`,
+    /// fetching at most `max_ids` of them.
+    pub fn query_bpf(&self, max_ids: u32) -> Result<Vec<u32>, SysErr> {
+        let ret: i32;
+        // Layout is `perf_event_query_bpf` followed
+        // by its flexible array of `ids_len` IDs:
+        // { ids_len, prog_cnt, ids[ids_len] }.
+        let mut buf = vec![0_u32; 2 + max_ids as usize];
+        buf[0] = max_ids;
+        let arg = buf.as_mut_ptr() as *mut perf_event_query_bpf;
+        ret = unsafe { libc::ioctl(self.fd, QUERY_BPF as u64, arg) };
+        if ret == -1 {
+            return Err(SysErr::IoFail(errno()));
+        }
+        let count = buf[1].min(max_ids) as usize;
+        Ok(buf[2..2 + count].to_vec())
+    }
+    /// Read the counter value(s) associated with
+    /// field of `FileDesc` caller, decoded according
+    /// to the `read_format` the event was opened with.
+    pub fn read(&self) -> Result<Reading, SysErr> {
+        let group = self.read_format & perf_event_read_format_PERF_FORMAT_GROUP as u64 != 0;
+        // A group read needs room for every member, and we
+        // don't know how many there are; grow until it fits.
+        let mut buf = vec![0_u64; if group { 64 } else { 4 }];
+        while read_wrap(self.fd, &mut buf) == -1 {
+            let errno = errno();
+            if !group || errno != libc::ENOSPC {
+                return Err(SysErr::ReadFail(errno));
+            }
+            buf.resize(buf.len() * 2, 0);
+        }
+        Ok(Reading::decode(self.read_format, &mut buf.iter().copied()))
+    }
+}
+
+impl FileDesc {
+    /// Duplicate the file descriptor. The copy refers
+    /// to the same counter, and is closed independently.
+    pub fn try_clone(&self) -> Result<Self, SysErr> {
+        let ret = unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if ret == -1 {
+            return Err(SysErr::IoFail(errno()));
+        }
+        Ok(Self {
+            fd: ret,
+            read_format: self.read_format,
+            sample_type: self.sample_type,
+        })
+    }
+    /// The `read_format` the event was opened with.
+    pub fn read_format(&self) -> u64 {
+        self.read_format
+    }
+    /// The `sample_type` the event was opened with.
+    /// Determines the layout of `PERF_RECORD_SAMPLE` records.
+    pub fn sample_type(&self) -> u64 {
+        self.sample_type
+    }
+}
+
+impl AsRawFd for FileDesc {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+/// Give up ownership of the descriptor.
+/// The caller becomes responsible for closing it.
+impl IntoRawFd for FileDesc {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.fd;
+        std::mem::forget(self);
+        fd
+    }
+}
+
+/// Close the descriptor, stopping and
+/// freeing the counter in the kernel.
+impl Drop for FileDesc {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            unsafe { libc::close(self.fd) };
         }
-        Ok(ret)
     }
 }
 
@@ -216,3 +547,226 @@ fn read_test() {
     assert_ne!(cnt, 0);
     assert!(cnt > 0, "cnt = {}", cnt);
 }
+
+#[test]
+fn decode_group_test() {
+    let read_format = (perf_event_read_format_PERF_FORMAT_GROUP
+        | perf_event_read_format_PERF_FORMAT_ID
+        | perf_event_read_format_PERF_FORMAT_TOTAL_TIME_ENABLED
+        | perf_event_read_format_PERF_FORMAT_TOTAL_TIME_RUNNING) as u64;
+    // { nr, time_enabled, time_running, { value, id } * nr }
+    let buf = [2, 1000, 500, 10, 7, 20, 8];
+    let reading = Reading::decode(read_format, &mut buf.iter().copied());
+    assert_eq!(reading.time_enabled, 1000);
+    assert_eq!(reading.time_running, 500);
+    assert_eq!(reading.values.len(), 2);
+    assert_eq!(reading.values[1].id, 8);
+    assert_eq!(reading.scaled(0), Some(20));
+    assert_eq!(reading.scaled(1), Some(40));
+    assert!((reading.running_ratio() - 0.5).abs() < f64::EPSILON);
+}
+
+#[test]
+fn reading_since_test() {
+    let read_format = (perf_event_read_format_PERF_FORMAT_TOTAL_TIME_ENABLED
+        | perf_event_read_format_PERF_FORMAT_TOTAL_TIME_RUNNING) as u64;
+    let start = Reading::decode(read_format, &mut [5, 100, 100].iter().copied());
+    let stop = Reading::decode(read_format, &mut [25, 300, 100].iter().copied());
+    let delta = stop.since(&start);
+    assert_eq!(delta.value(), 20);
+    assert_eq!(delta.time_running, 0);
+    assert_eq!(delta.scaled(0), None);
+    // More taken away than was counted, as when a thread's exit is
+    // seen after the reading it should have been part of.
+    let under = start.since(&stop);
+    assert_eq!((under.value(), under.time_enabled), (0, 0));
+    // Never ran, e.g. an idle thread: not a count of 0.
+    assert_eq!(under.scaled(0), None);
+    assert_eq!(start.since(&start).scaled(0), None);
+}
+
+#[test]
+fn reading_add_test() {
+    let read_format = (perf_event_read_format_PERF_FORMAT_TOTAL_TIME_ENABLED
+        | perf_event_read_format_PERF_FORMAT_TOTAL_TIME_RUNNING) as u64;
+    let mut total = Reading::default();
+    total.add(&Reading::decode(
+        read_format,
+        &mut [10, 100, 50].iter().copied(),
+    ));
+    total.add(&Reading::decode(
+        read_format,
+        &mut [30, 100, 100].iter().copied(),
+    ));
+    assert_eq!(total.value(), 40);
+    assert_eq!((total.time_enabled, total.time_running), (200, 150));
+    assert_eq!(total.scaled(0), Some(53));
+}
+
+#[test]
+fn open_error_test() {
+    use crate::event::spec::EventSpec;
+    let event = &mut perf_event_attr {
+        // No PMU registers this type.
+        type_: u32::MAX,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        ..Default::default()
+    };
+    let err = FileDesc::new(event, None, -1, -1).unwrap_err();
+    assert_eq!(err.errno(), Some(libc::ENOENT));
+    assert!(err.is_unsupported());
+    assert!(err.hint().is_some());
+    match err {
+        SysErr::Open { attr, .. } => assert_eq!(attr.0.type_, u32::MAX),
+        _ => panic!("expected SysErr::Open, got {:?}", err),
+    }
+
+    // No CPU has stores to its instruction cache;
+    // x86 refuses them with EINVAL.
+    let event = &mut EventSpec::cache(
+        perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1I,
+        perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_WRITE,
+        perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_ACCESS,
+    )
+    .attr();
+    let err = FileDesc::new(event, None, -1, -1).unwrap_err();
+    assert!(err.is_unsupported(), "{}", err);
+}
+
+#[test]
+fn try_clone_test() {
+    let event = &mut perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_SOFTWARE,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        config: perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK as u64,
+        ..Default::default()
+    };
+    let fd = FileDesc::new(event, None, -1, -1).unwrap();
+    let clone = fd.try_clone().unwrap();
+    assert_ne!(fd.as_raw_fd(), clone.as_raw_fd());
+    drop(fd);
+    // The copy still refers to a live counter.
+    assert!(clone.read().unwrap().value() > 0);
+    let raw = clone.into_raw_fd();
+    assert_ne!(unsafe { libc::fcntl(raw, libc::F_GETFD) }, -1);
+    assert_eq!(unsafe { libc::close(raw) }, 0);
+}
+
+/// Returns true, after logging why, if an ioctl test
+/// should be skipped because this kernel lacks support.
+#[cfg(test)]
+fn unsupported(err: &SysErr, what: &str) -> bool {
+    match err.errno() {
+        Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) | Some(libc::ENOENT) | Some(libc::EACCES) => {
+            eprintln!("skipping {}: {}", what, err);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Open a task-clock sampling event for the ioctl tests.
+#[cfg(test)]
+fn sampling_event() -> FileDesc {
+    let event = &mut perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_SOFTWARE,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        config: perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK as u64,
+        __bindgen_anon_1: perf_event_attr__bindgen_ty_1 {
+            sample_period: 100_000,
+        },
+        sample_type: perf_event_sample_format_PERF_SAMPLE_IP,
+        ..Default::default()
+    };
+    event.set_disabled(1);
+    event.set_exclude_kernel(1);
+    FileDesc::new(event, None, -1, -1).unwrap()
+}
+
+#[test]
+fn set_output_test() {
+    use crate::event::ring::RingBuffer;
+    let leader = sampling_event();
+    let follower = sampling_event();
+    let _ring = RingBuffer::new(&leader, 1).unwrap();
+    if let Err(e) = follower.set_output(&leader) {
+        assert!(unsupported(&e, "set_output_test"), "{}", e);
+        return;
+    }
+    follower.ignore_output().unwrap();
+}
+
+#[test]
+fn pause_output_test() {
+    use crate::event::ring::RingBuffer;
+    let fd = sampling_event();
+    let ring = RingBuffer::new(&fd, 1).unwrap();
+    if let Err(e) = fd.pause_output() {
+        assert!(unsupported(&e, "pause_output_test"), "{}", e);
+        return;
+    }
+    fd.enable().unwrap();
+    let start = std::time::Instant::now();
+    while start.elapsed().as_millis() < 5 {}
+    fd.disable().unwrap();
+    // Nothing is written while output is paused.
+    assert!(ring.is_empty());
+    fd.resume_output().unwrap();
+}
+
+#[test]
+fn modify_attributes_test() {
+    static WATCHED: [u64; 2] = [0, 0];
+    let event = &mut perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_BREAKPOINT,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        bp_type: HW_BREAKPOINT_W,
+        __bindgen_anon_3: perf_event_attr__bindgen_ty_3 {
+            bp_addr: &WATCHED[0] as *const u64 as u64,
+        },
+        __bindgen_anon_4: perf_event_attr__bindgen_ty_4 {
+            bp_len: HW_BREAKPOINT_LEN_8 as u64,
+        },
+        ..Default::default()
+    };
+    event.set_disabled(1);
+    event.set_exclude_kernel(1);
+    event.set_exclude_hv(1);
+    let fd = match FileDesc::new(event, None, -1, -1) {
+        Ok(fd) => fd,
+        Err(e) => {
+            assert!(unsupported(&e, "modify_attributes_test"), "{}", e);
+            return;
+        }
+    };
+    event.__bindgen_anon_3.bp_addr = &WATCHED[1] as *const u64 as u64;
+    if let Err(e) = fd.modify_attributes(event) {
+        assert!(unsupported(&e, "modify_attributes_test"), "{}", e);
+        return;
+    }
+    // Only breakpoints can be modified.
+    let err = sampling_event().modify_attributes(event).unwrap_err();
+    assert!(err.errno().is_some());
+}
+
+#[test]
+fn set_filter_test() {
+    // The filter ioctl is only valid on tracepoints;
+    // everything else is rejected with EINVAL.
+    let fd = sampling_event();
+    let err = fd.set_filter("common_pid == 1").unwrap_err();
+    assert_eq!(err.errno(), Some(libc::EINVAL));
+    assert!(matches!(fd.set_filter("nul\0byte"), Err(SysErr::IoArg)));
+}
+
+#[test]
+fn bpf_test() {
+    // Neither ioctl is valid on a software event, but
+    // the kernel must recognise them rather than
+    // answer ENOTTY.
+    let fd = sampling_event();
+    let err = fd.set_bpf(-1).unwrap_err();
+    assert_ne!(err.errno(), Some(libc::ENOTTY));
+    let err = fd.query_bpf(4).unwrap_err();
+    assert_ne!(err.errno(), Some(libc::ENOTTY));
+}
@@ -6,9 +6,17 @@
 // Disable cargo build warnings created due to using bindgen.
 #![allow(dead_code)]
 
+mod cpus;
 mod fd;
+mod names;
 pub mod open;
+mod parse;
+mod pmu;
+mod pmu_events;
+mod ring;
+mod spec;
 mod sys;
+mod tracepoint;
 mod utils;
 
 pub fn perf_event_hello() {
@@ -31,13 +39,12 @@ fn wrapper_test() {
     event.set_disabled(1);
     event.set_exclude_kernel(1);
     event.set_exclude_hv(1);
-    // Panic on failure.
-    let fd = fd::FileDesc::new(event, Some(0), -1, -1);
+    let fd = fd::FileDesc::new(event, Some(0), -1, -1).unwrap();
     // Make sure ioctls are working.
     fd.reset().unwrap();
     fd.disable().unwrap();
     fd.enable().unwrap();
-    let cnt: isize = fd.read().unwrap();
+    let cnt = fd.read().unwrap().value();
     fd.id().unwrap();
     // change overflow sampling period
     fd.overflow_period(2).unwrap();
@@ -0,0 +1,358 @@
+//! The symbolic event names the event parser understands,
+//! spelled the way perf spells them so existing perf
+//! command lines can be reused.
+
+use crate::bindings::*;
+use crate::event::spec::{cache_config, EventSpec};
+
+/// A generalized event, under perf's name for it
+/// followed by any aliases.
+struct Symbol {
+    names: &'static [&'static str],
+    type_: u32,
+    config: u64,
+}
+
+const fn hw(names: &'static [&'static str], id: perf_hw_id) -> Symbol {
+    Symbol {
+        names,
+        type_: perf_type_id_PERF_TYPE_HARDWARE,
+        config: id as u64,
+    }
+}
+
+const fn sw(names: &'static [&'static str], id: perf_sw_ids) -> Symbol {
+    Symbol {
+        names,
+        type_: perf_type_id_PERF_TYPE_SOFTWARE,
+        config: id as u64,
+    }
+}
+
+const fn cache(
+    names: &'static [&'static str],
+    id: perf_hw_cache_id,
+    op: perf_hw_cache_op_id,
+    result: perf_hw_cache_op_result_id,
+) -> Symbol {
+    Symbol {
+        names,
+        type_: perf_type_id_PERF_TYPE_HW_CACHE,
+        config: cache_config(id, op, result),
+    }
+}
+
+/// Every `perf_hw_id` and `perf_sw_ids` event.
+const SYMBOLS: &[Symbol] = &[
+    hw(
+        &["cpu-cycles", "cycles"],
+        perf_hw_id_PERF_COUNT_HW_CPU_CYCLES,
+    ),
+    hw(&["instructions"], perf_hw_id_PERF_COUNT_HW_INSTRUCTIONS),
+    hw(
+        &["cache-references"],
+        perf_hw_id_PERF_COUNT_HW_CACHE_REFERENCES,
+    ),
+    hw(&["cache-misses"], perf_hw_id_PERF_COUNT_HW_CACHE_MISSES),
+    hw(
+        &["branch-instructions", "branches"],
+        perf_hw_id_PERF_COUNT_HW_BRANCH_INSTRUCTIONS,
+    ),
+    hw(&["branch-misses"], perf_hw_id_PERF_COUNT_HW_BRANCH_MISSES),
+    hw(&["bus-cycles"], perf_hw_id_PERF_COUNT_HW_BUS_CYCLES),
+    hw(
+        &["stalled-cycles-frontend", "idle-cycles-frontend"],
+        perf_hw_id_PERF_COUNT_HW_STALLED_CYCLES_FRONTEND,
+    ),
+    hw(
+        &["stalled-cycles-backend", "idle-cycles-backend"],
+        perf_hw_id_PERF_COUNT_HW_STALLED_CYCLES_BACKEND,
+    ),
+    hw(&["ref-cycles"], perf_hw_id_PERF_COUNT_HW_REF_CPU_CYCLES),
+    sw(&["cpu-clock"], perf_sw_ids_PERF_COUNT_SW_CPU_CLOCK),
+    sw(&["task-clock"], perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK),
+    sw(
+        &["page-faults", "faults"],
+        perf_sw_ids_PERF_COUNT_SW_PAGE_FAULTS,
+    ),
+    sw(
+        &["context-switches", "cs"],
+        perf_sw_ids_PERF_COUNT_SW_CONTEXT_SWITCHES,
+    ),
+    sw(
+        &["cpu-migrations", "migrations"],
+        perf_sw_ids_PERF_COUNT_SW_CPU_MIGRATIONS,
+    ),
+    sw(&["minor-faults"], perf_sw_ids_PERF_COUNT_SW_PAGE_FAULTS_MIN),
+    sw(&["major-faults"], perf_sw_ids_PERF_COUNT_SW_PAGE_FAULTS_MAJ),
+    sw(
+        &["alignment-faults"],
+        perf_sw_ids_PERF_COUNT_SW_ALIGNMENT_FAULTS,
+    ),
+    sw(
+        &["emulation-faults"],
+        perf_sw_ids_PERF_COUNT_SW_EMULATION_FAULTS,
+    ),
+    sw(&["dummy"], perf_sw_ids_PERF_COUNT_SW_DUMMY),
+    sw(&["bpf-output"], perf_sw_ids_PERF_COUNT_SW_BPF_OUTPUT),
+];
+
+/// ruperf's own names for a few cache events, kept so older
+/// command lines still work; perf calls them `L1-dcache-*`.
+const LEGACY: &[Symbol] = &[
+    cache(
+        &["L1D-cache-reads"],
+        perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
+        perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
+        perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_ACCESS,
+    ),
+    cache(
+        &["L1D-cache-writes"],
+        perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
+        perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_WRITE,
+        perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_ACCESS,
+    ),
+    cache(
+        &["L1D-cache-read-misses"],
+        perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
+        perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
+        perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_MISS,
+    ),
+    cache(
+        &["L1I-cache-read-misses"],
+        perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1I,
+        perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
+        perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_MISS,
+    ),
+];
+
+impl Symbol {
+    #[allow(non_upper_case_globals)]
+    fn spec(&self, name: &str) -> EventSpec {
+        let spec = match self.type_ {
+            perf_type_id_PERF_TYPE_SOFTWARE => EventSpec::software(self.config as perf_sw_ids),
+            type_ => EventSpec::new(type_, self.config),
+        };
+        spec.name(name)
+    }
+}
+
+/// Spellings perf accepts for each `perf_hw_cache_id`.
+const CACHE_IDS: &[(perf_hw_cache_id, &[&str])] = &[
+    (
+        perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
+        &["L1-dcache", "l1-d", "l1d", "L1-data"],
+    ),
+    (
+        perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1I,
+        &["L1-icache", "l1-i", "l1i", "L1-instruction"],
+    ),
+    (perf_hw_cache_id_PERF_COUNT_HW_CACHE_LL, &["LLC", "L2"]),
+    (
+        perf_hw_cache_id_PERF_COUNT_HW_CACHE_DTLB,
+        &["dTLB", "d-tlb", "Data-TLB"],
+    ),
+    (
+        perf_hw_cache_id_PERF_COUNT_HW_CACHE_ITLB,
+        &["iTLB", "i-tlb", "Instruction-TLB"],
+    ),
+    (
+        perf_hw_cache_id_PERF_COUNT_HW_CACHE_BPU,
+        &["branch", "branches", "bpu", "btb", "bpc"],
+    ),
+    (perf_hw_cache_id_PERF_COUNT_HW_CACHE_NODE, &["node"]),
+];
+
+/// Spellings perf accepts for each `perf_hw_cache_op_id`.
+const CACHE_OPS: &[(perf_hw_cache_op_id, &[&str])] = &[
+    (
+        perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
+        &["load", "loads", "read"],
+    ),
+    (
+        perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_WRITE,
+        &["store", "stores", "write"],
+    ),
+    (
+        perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_PREFETCH,
+        &[
+            "prefetch",
+            "prefetches",
+            "speculative-read",
+            "speculative-load",
+        ],
+    ),
+];
+
+/// Spellings perf accepts for each `perf_hw_cache_op_result_id`.
+const CACHE_RESULTS: &[(perf_hw_cache_op_result_id, &[&str])] = &[
+    (
+        perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_ACCESS,
+        &["refs", "Reference", "ops", "access"],
+    ),
+    (
+        perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_MISS,
+        &["misses", "miss"],
+    ),
+];
+
+/// Every cache event, under perf's name for it:
+/// `<cache>-<op>s` for accesses, e.g. `dTLB-loads`,
+/// and `<cache>-<op>-misses` for misses, e.g. `LLC-load-misses`.
+/// Not every CPU supports every combination.
+pub fn cache_events() -> Vec<EventSpec> {
+    let mut events = Vec::new();
+    for (id, cache) in CACHE_IDS {
+        for (op, ops) in CACHE_OPS {
+            for (result, results) in CACHE_RESULTS {
+                let name = if *result == perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_MISS
+                {
+                    format!("{}-{}-{}", cache[0], ops[0], results[0])
+                } else {
+                    format!("{}-{}", cache[0], ops[1])
+                };
+                events.push(EventSpec::cache(*id, *op, *result).name(&name));
+            }
+        }
+    }
+    events
+}
+
+/// Every generalized hardware event, then every
+/// software event, under perf's name for it.
+pub fn generic_events() -> Vec<EventSpec> {
+    SYMBOLS.iter().map(|s| s.spec(s.names[0])).collect()
+}
+
+/// Other names perf accepts for the generic event `name`.
+pub fn aliases(name: &str) -> &'static [&'static str] {
+    match SYMBOLS.iter().find(|s| s.names[0] == name) {
+        Some(symbol) => &symbol.names[1..],
+        None => &[],
+    }
+}
+
+/// Look up a hardware, software or cache event by name.
+pub fn lookup(name: &str) -> Option<EventSpec> {
+    match SYMBOLS
+        .iter()
+        .chain(LEGACY)
+        .find(|s| s.names.contains(&name))
+    {
+        Some(symbol) => Some(symbol.spec(name)),
+        None => Some(cache_event(name)?.name(name)),
+    }
+}
+
+/// Parse a perf cache event name, `cache[-op][-result]`,
+/// e.g. `L1-dcache-load-misses` or `dTLB-loads`.
+/// A missing op means read, a missing result means access.
+fn cache_event(name: &str) -> Option<EventSpec> {
+    let (id, rest) = strip_alias(CACHE_IDS, name)?;
+    let (op, rest) = match rest
+        .strip_prefix('-')
+        .and_then(|r| strip_alias(CACHE_OPS, r))
+    {
+        Some((op, rest)) => (op, rest),
+        None => (perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ, rest),
+    };
+    let (result, rest) = match rest
+        .strip_prefix('-')
+        .and_then(|r| strip_alias(CACHE_RESULTS, r))
+    {
+        Some((result, rest)) => (result, rest),
+        None => (
+            perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_ACCESS,
+            rest,
+        ),
+    };
+    if !rest.is_empty() {
+        return None;
+    }
+    Some(EventSpec::cache(id, op, result))
+}
+
+/// Strip the longest alias in `table` that is a whole
+/// `-` separated prefix of `name`, returning its value.
+fn strip_alias<'a>(table: &[(u32, &[&str])], name: &'a str) -> Option<(u32, &'a str)> {
+    table
+        .iter()
+        .flat_map(|(value, aliases)| aliases.iter().map(move |a| (*value, *a)))
+        .filter(|(_, alias)| {
+            name.starts_with(alias)
+                && matches!(name[alias.len()..].chars().next(), None | Some('-'))
+        })
+        .max_by_key(|(_, alias)| alias.len())
+        .map(|(value, alias)| (value, &name[alias.len()..]))
+}
+
+#[cfg(test)]
+#[test]
+fn cache_event_test() {
+    let config = |name| lookup(name).unwrap().config;
+    assert_eq!(config("L1-dcache-load-misses"), 0x10000);
+    assert_eq!(config("L1-dcache-loads"), 0x0);
+    assert_eq!(config("LLC-store-misses"), 0x10102);
+    assert_eq!(config("dTLB-prefetches"), 0x203);
+    assert_eq!(config("d-tlb-misses"), 0x10003);
+    assert_eq!(config("branch-load-misses"), 0x10005);
+    assert_eq!(config("node-speculative-read-miss"), 0x10206);
+    assert_eq!(config("L1D-cache-reads"), 0x0);
+    assert!(lookup("L1-dcache-bogus").is_none());
+    assert!(lookup("L1-dcachex").is_none());
+}
+
+#[test]
+fn generic_events_test() {
+    let events = generic_events();
+    let hardware = events
+        .iter()
+        .filter(|e| e.type_ == perf_type_id_PERF_TYPE_HARDWARE)
+        .count();
+    assert_eq!(hardware, 10);
+    assert_eq!(events.len() - hardware, 11);
+    assert_eq!(
+        lookup("branches").unwrap().config,
+        lookup("branch-instructions").unwrap().config
+    );
+    assert_eq!(
+        lookup("faults").unwrap().config,
+        lookup("page-faults").unwrap().config
+    );
+}
+
+#[test]
+fn symbol_test() {
+    let spec = lookup("cs").unwrap();
+    assert_eq!(spec.name, "cs");
+    assert_eq!(spec.type_, perf_type_id_PERF_TYPE_SOFTWARE);
+    assert!(!spec.exclude_kernel);
+    let spec = lookup("cycles").unwrap();
+    assert_eq!(spec.type_, perf_type_id_PERF_TYPE_HARDWARE);
+    assert!(spec.exclude_kernel);
+}
+
+#[test]
+fn cache_events_test() {
+    let events = cache_events();
+    assert_eq!(events.len(), 7 * 3 * 2);
+    for event in &events {
+        let parsed = lookup(&event.name).unwrap();
+        assert_eq!(parsed.config, event.config, "{}", event.name);
+    }
+    let names: Vec<&str> = events.iter().map(|e| e.name.as_str()).collect();
+    for name in &[
+        "L1-dcache-loads",
+        "L1-dcache-load-misses",
+        "L1-icache-load-misses",
+        "LLC-load-misses",
+        "LLC-prefetches",
+        "dTLB-loads",
+        "dTLB-store-misses",
+        "iTLB-load-misses",
+        "branch-load-misses",
+        "node-loads",
+    ] {
+        assert!(names.contains(name), "{} missing", name);
+    }
+}
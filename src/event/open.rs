@@ -7,162 +7,115 @@
 
 use crate::bindings::*;
 use crate::event::fd;
-use crate::event::utils::*;
 use crate::stat::StatEvent;
+use std::os::unix::io::AsRawFd;
 
-const PERF_EVENT_ATTR_SIZE: u32 = std::mem::size_of::<perf_event_attr>() as u32;
+pub use crate::event::cpus::{online_cpus, parse_cpu_list};
+pub use crate::event::fd::Reading;
+pub use crate::event::names::{aliases, cache_events, generic_events, lookup};
+pub use crate::event::parse::parse_events;
+pub use crate::event::pmu::{Pmu, EVENT_SOURCE};
+pub use crate::event::pmu_events::{PmuEvents, VendorMetric, PMU_EVENTS_VAR};
+pub use crate::event::ring::{Record, RingBuffer};
+pub use crate::event::spec::EventSpec;
+pub use crate::event::tracepoint::{tracefs_events, tracepoints};
+#[cfg(test)]
+pub use crate::event::utils::fixture;
+pub use crate::event::utils::{EventErr, SyntaxErr, SysErr};
+
+/// `read_format` for every event, so counts can be scaled
+/// when the kernel multiplexes more events than the PMU has counters.
+const SCALE_READ_FORMAT: u64 = (perf_event_read_format_PERF_FORMAT_TOTAL_TIME_ENABLED
+    | perf_event_read_format_PERF_FORMAT_TOTAL_TIME_RUNNING) as u64;
+
+/// `read_format` for events opened as part of an `EventGroup`,
+/// so the whole group can be read atomically from its leader.
+const GROUP_READ_FORMAT: u64 = SCALE_READ_FORMAT
+    | (perf_event_read_format_PERF_FORMAT_GROUP | perf_event_read_format_PERF_FORMAT_ID) as u64;
 
-///Event enum contains file descriptor and event type
-//simple starting options. Add more as needed
+/// An open event: its file descriptor
+/// and the spec it was opened from.
 pub struct Event {
     pub fd: fd::FileDesc,
-    pub event: StatEvent,
+    pub spec: EventSpec,
 }
 
-/// Initialize perf attributes. Currently set up to match on intended event.
-/// Returns the initialized perf_event_attr data structure or an error.
-pub fn event_open(event: &StatEvent) -> Result<perf_event_attr, EventErr> {
-    // To measure hardware CPU cache events
-    // when `type_` is set to `PERF_TYPE_HW_CACHE`
-    // the value of config must be computed.
-    // See the `perf_event_open()` man page for details.
-    let cache_config = |id, op, rs| (id as u64) | ((op as u64) << 8) | ((rs as u64) << 16);
-    match &event {
-        StatEvent::Cycles => {
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_HARDWARE,
-                size: PERF_EVENT_ATTR_SIZE,
-                config: perf_hw_id_PERF_COUNT_HW_CPU_CYCLES as u64,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::Instructions => {
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_HARDWARE,
-                size: PERF_EVENT_ATTR_SIZE,
-                config: perf_hw_id_PERF_COUNT_HW_INSTRUCTIONS as u64,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::TaskClock => {
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_SOFTWARE,
-                size: std::mem::size_of::<perf_event_attr>() as u32,
-                config: perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK as u64,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::ContextSwitches => {
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_SOFTWARE,
-                size: std::mem::size_of::<perf_event_attr>() as u32,
-                config: perf_sw_ids_PERF_COUNT_SW_CONTEXT_SWITCHES as u64,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(0);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::L1DCacheRead => {
-            let config: u64 = cache_config(
-                perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
-                perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
-                perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_ACCESS,
-            );
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_HW_CACHE,
-                size: PERF_EVENT_ATTR_SIZE,
-                config,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::L1DCacheWrite => {
-            let config: u64 = cache_config(
-                perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
-                perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_WRITE,
-                perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_ACCESS,
-            );
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_HW_CACHE,
-                size: PERF_EVENT_ATTR_SIZE,
-                config,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::L1DCacheReadMiss => {
-            let config: u64 = cache_config(
-                perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
-                perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
-                perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_MISS,
-            );
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_HW_CACHE,
-                size: PERF_EVENT_ATTR_SIZE,
-                config,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::L1ICacheReadMiss => {
-            let config: u64 = cache_config(
-                perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1I,
-                perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
-                perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_MISS,
-            );
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_HW_CACHE,
-                size: PERF_EVENT_ATTR_SIZE,
-                config,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
+impl Event {
+    /// Construct a new event from a spec,
+    /// or from anything that names one, like a `StatEvent`.
+    pub fn new(spec: impl Into<EventSpec>, pid: Option<i32>) -> Result<Self, EventErr> {
+        Self::open(spec.into(), pid, -1, SCALE_READ_FORMAT, -1)
+    }
+    /// Construct a new event whose counter can be read
+    /// with a group read. If `group_fd` is -1 the event
+    /// becomes a group leader, otherwise it joins the group
+    /// led by `group_fd`.
+    fn new_grouped(
+        spec: EventSpec,
+        pid: Option<i32>,
+        cpu: i32,
+        group_fd: i32,
+    ) -> Result<Self, EventErr> {
+        Self::open(spec, pid, cpu, GROUP_READ_FORMAT, group_fd)
+    }
+    /// Open `spec` with the given `read_format`, naming
+    /// the event in the error if `perf_event_open()` fails.
+    fn open(
+        spec: EventSpec,
+        pid: Option<i32>,
+        cpu: i32,
+        read_format: u64,
+        group_fd: i32,
+    ) -> Result<Self, EventErr> {
+        let e: &mut perf_event_attr = &mut spec.attr();
+        e.read_format = read_format;
+        match fd::FileDesc::new(e, pid, cpu, group_fd) {
+            Ok(fd) => Ok(Self { fd, spec }),
+            Err(source) => Err(EventErr::Open {
+                event: spec.to_string(),
+                source,
+            }),
         }
     }
-}
-
-impl Event {
-    /// Construct a new event.
-    pub fn new(event: StatEvent, pid: Option<i32>) -> Self {
-        let e: &mut perf_event_attr = &mut event_open(&event).unwrap();
-        let fd = fd::FileDesc::new(e, pid, -1, -1);
-        Self { fd, event }
+    /// Count accesses to `var` in this process, such as
+    /// writes to a hot shared variable, with a hardware breakpoint.
+    /// `bp_type` is the accesses to count, e.g. `HW_BREAKPOINT_W`.
+    /// `T` must be 1, 2, 4 or 8 bytes, the lengths a breakpoint can cover.
+    pub fn watch<T>(var: &T, bp_type: u32) -> Result<Self, EventErr> {
+        let (addr, len) = watched(var)?;
+        Self::new(EventSpec::breakpoint(addr, len, bp_type), None)
+    }
+    /// Move a breakpoint from `watch` on to `var`, in place.
+    /// Counts carry on from where they were. Like
+    /// opening it, this leaves the breakpoint disabled
+    /// until the next `start_counter`.
+    pub fn rewatch<T>(&mut self, var: &T) -> Result<(), EventErr> {
+        if self.spec.type_ != perf_type_id_PERF_TYPE_BREAKPOINT {
+            return Err(EventErr::InvalidEvent);
+        }
+        let (addr, len) = watched(var)?;
+        let mut spec = self.spec.clone();
+        spec.config1 = addr;
+        spec.config2 = len;
+        // Keep the name, `mem:0xADDR/len:rwx`, up to date.
+        spec.name = EventSpec::breakpoint(addr, len, spec.bp_type).name;
+        // The kernel refuses the change unless every
+        // other attribute matches the ones it was opened with.
+        let e: &mut perf_event_attr = &mut spec.attr();
+        e.read_format = self.fd.read_format();
+        self.fd.modify_attributes(e)?;
+        self.spec = spec;
+        Ok(())
     }
     /// Start the counter on an event.
-    pub fn start_counter(&self) -> Result<isize, SysErr> {
+    pub fn start_counter(&self) -> Result<Reading, SysErr> {
         match self.fd.enable() {
             Ok(_) => self.fd.read(),
             Err(e) => Err(e),
         }
     }
     ///Stop the counter on an event.
-    pub fn stop_counter(&self) -> Result<isize, SysErr> {
+    pub fn stop_counter(&self) -> Result<Reading, SysErr> {
         match self.fd.disable() {
             Ok(_) => self.fd.read(),
             Err(e) => Err(e),
@@ -178,85 +131,321 @@ impl Event {
     }
 }
 
+/// The address and breakpoint length of a variable to watch.
+fn watched<T>(var: &T) -> Result<(u64, u64), EventErr> {
+    match std::mem::size_of_val(var) {
+        len @ 1 | len @ 2 | len @ 4 | len @ 8 => Ok((var as *const T as u64, len as u64)),
+        _ => Err(EventErr::InvalidEvent),
+    }
+}
+
+/// A set of events the kernel schedules onto the PMU together,
+/// so every member counts over exactly the same window.
+/// The first event is the group leader; enabling, disabling
+/// and reading all go through the leader.
+pub struct EventGroup {
+    pub events: Vec<Event>,
+    ids: Vec<u64>,
+}
+
+impl EventGroup {
+    /// Construct a new group from `events`. The first event
+    /// becomes the leader, so there must be at least one.
+    pub fn new(events: &[EventSpec], pid: Option<i32>) -> Result<Self, EventErr> {
+        Self::on_cpu(events, pid, -1)
+    }
+    /// Construct a new group counting only on `cpu`, or
+    /// on any CPU if it is -1. A `pid` of -1 counts every
+    /// process on `cpu`, which is how system-wide counting is done.
+    /// Fails with `InvalidEvent` if `events` is empty.
+    pub fn on_cpu(events: &[EventSpec], pid: Option<i32>, cpu: i32) -> Result<Self, EventErr> {
+        let (first, rest) = events.split_first().ok_or(EventErr::InvalidEvent)?;
+        let leader = Event::new_grouped(first.clone(), pid, cpu, -1)?;
+        let leader_fd = leader.fd.as_raw_fd();
+        let mut group = vec![leader];
+        for event in rest {
+            group.push(Event::new_grouped(event.clone(), pid, cpu, leader_fd)?);
+        }
+        let ids = group
+            .iter()
+            .map(|e| e.fd.id().map(|id| id as u64))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { events: group, ids })
+    }
+    /// The event every other member is scheduled with.
+    pub fn leader(&self) -> &Event {
+        &self.events[0]
+    }
+    /// Start every counter in the group at once.
+    /// Returns counts in the same order as `events`.
+    pub fn start_counter(&self) -> Result<Reading, SysErr> {
+        self.leader().fd.enable_group()?;
+        self.read()
+    }
+    /// Stop every counter in the group at once.
+    /// Returns counts in the same order as `events`.
+    pub fn stop_counter(&self) -> Result<Reading, SysErr> {
+        self.leader().fd.disable_group()?;
+        self.read()
+    }
+    /// Reset every counter in the group to 0.
+    pub fn reset_counter(&self) -> Result<(), SysErr> {
+        self.leader().fd.reset_group()
+    }
+    /// Read the whole group, while it counts or not,
+    /// and put the values back in member order.
+    pub fn read(&self) -> Result<Reading, SysErr> {
+        let reading = self.leader().fd.read()?;
+        self.member_order(reading).ok_or(SysErr::IoId)
+    }
+    /// Put the values of a group read, such as one from a
+    /// `PERF_RECORD_READ`, back in member order, matching them
+    /// up by ID. Returns `None` if it isn't a read of this group.
+    pub fn member_order(&self, mut reading: Reading) -> Option<Reading> {
+        let values = self
+            .ids
+            .iter()
+            .map(|id| reading.values.iter().find(|v| v.id == *id).copied())
+            .collect::<Option<Vec<_>>>()?;
+        reading.values = values;
+        Some(reading)
+    }
+}
+
 #[cfg(test)]
 #[test]
 fn cycles_open_test() {
-    let event = Event::new(StatEvent::Cycles, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(StatEvent::Cycles, None).unwrap();
+    let cnt = event.start_counter().unwrap().value();
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().value();
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
 
 #[test]
 fn inst_open_test() {
-    let event = Event::new(StatEvent::Instructions, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(StatEvent::Instructions, None).unwrap();
+    let cnt = event.start_counter().unwrap().value();
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().value();
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
 
 #[test]
 fn taskclock_open_test() {
-    let event = Event::new(StatEvent::TaskClock, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(StatEvent::TaskClock, None).unwrap();
+    let cnt = event.start_counter().unwrap().value();
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().value();
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
 fn l1_data_cache_read_open_test() {
-    let event = Event::new(StatEvent::L1DCacheRead, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(StatEvent::L1DCacheRead, None).unwrap();
+    let cnt = event.start_counter().unwrap().value();
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().value();
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
 
 #[test]
 fn cs_open_test() {
-    let event = Event::new(StatEvent::ContextSwitches, None);
-    let cnt: isize = event.start_counter().unwrap();
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
-    assert_ne!(cnt_2, -1);
+    let event = Event::new(StatEvent::ContextSwitches, None).unwrap();
+    let cnt = event.start_counter().unwrap().value();
+    let cnt_2 = event.stop_counter().unwrap().value();
+    assert!(cnt <= cnt_2);
 }
 fn l1_data_cache_write_open_test() {
-    let event = Event::new(StatEvent::L1DCacheWrite, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(StatEvent::L1DCacheWrite, None).unwrap();
+    let cnt = event.start_counter().unwrap().value();
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().value();
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
 
 #[test]
 fn l1_data_cache_read_miss_open_test() {
-    let event = Event::new(StatEvent::L1DCacheReadMiss, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(StatEvent::L1DCacheReadMiss, None).unwrap();
+    let cnt = event.start_counter().unwrap().value();
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().value();
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
 
 #[test]
 fn l1_inst_cache_read_miss_open_test() {
-    let event = Event::new(StatEvent::L1ICacheReadMiss, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(StatEvent::L1ICacheReadMiss, None).unwrap();
+    let cnt = event.start_counter().unwrap().value();
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().value();
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
+
+#[test]
+fn group_open_test() {
+    let group = EventGroup::new(
+        &[StatEvent::Cycles.into(), StatEvent::Instructions.into()],
+        None,
+    )
+    .unwrap();
+    let start = group.start_counter().unwrap();
+    assert_eq!(start.values.len(), 2);
+    let stop = group.stop_counter().unwrap();
+    assert_eq!(stop.values.len(), 2);
+    assert!(start.values[0].value < stop.values[0].value);
+    assert!(start.values[1].value < stop.values[1].value);
+}
+
+#[test]
+fn sw_group_open_test() {
+    let group = EventGroup::new(
+        &[
+            StatEvent::TaskClock.into(),
+            StatEvent::ContextSwitches.into(),
+        ],
+        None,
+    )
+    .unwrap();
+    group.start_counter().unwrap();
+    let stop = group.stop_counter().unwrap();
+    assert_eq!(stop.values.len(), 2);
+    assert!(stop.value() > 0, "task clock = {}", stop.value());
+    assert!(stop.time_enabled >= stop.time_running);
+    group.reset_counter().unwrap();
+}
+
+#[test]
+fn spec_open_test() {
+    let spec = EventSpec::software(perf_sw_ids_PERF_COUNT_SW_PAGE_FAULTS).name("page-faults");
+    let event = Event::new(spec, None).unwrap();
+    assert_eq!(event.spec.to_string(), "page-faults");
+    event.start_counter().unwrap();
+    let pages = vec![1_u8; 1 << 20];
+    let stop = event.stop_counter().unwrap();
+    assert!(
+        stop.value() > 0,
+        "{} faults for {} bytes",
+        stop.value(),
+        pages.len()
+    );
+}
+
+#[test]
+fn watch_test() {
+    static mut HOT: [u64; 2] = [0, 0];
+    let hot = unsafe { &mut *std::ptr::addr_of_mut!(HOT) };
+    let mut event = Event::watch(&hot[0], HW_BREAKPOINT_W).unwrap();
+    let name = |var: &u64| format!("mem:{:#x}/8:w", var as *const u64 as u64);
+    assert_eq!(event.spec.name, name(&hot[0]));
+    event.start_counter().unwrap();
+    for i in 0..3 {
+        unsafe { std::ptr::write_volatile(&mut hot[0], i) };
+    }
+    assert_eq!(event.stop_counter().unwrap().value(), 3);
+    event.rewatch(&hot[1]).unwrap();
+    assert_eq!(event.spec.name, name(&hot[1]));
+    event.start_counter().unwrap();
+    unsafe {
+        std::ptr::write_volatile(&mut hot[0], 4);
+        std::ptr::write_volatile(&mut hot[1], 5);
+    }
+    assert_eq!(event.stop_counter().unwrap().value(), 4);
+    assert!(Event::watch(&[0u8; 3], HW_BREAKPOINT_W).is_err());
+    let mut clock = Event::new(StatEvent::TaskClock, None).unwrap();
+    assert!(clock.rewatch(&hot[0]).is_err());
+}
+
+#[test]
+fn system_wide_open_test() {
+    let cpu = online_cpus().unwrap()[0];
+    let group = EventGroup::on_cpu(&[StatEvent::TaskClock.into()], Some(-1), cpu);
+    match group {
+        Ok(group) => {
+            group.start_counter().unwrap();
+            group.stop_counter().unwrap();
+        }
+        // Only allowed with perf_event_paranoid <= 0 or CAP_PERFMON.
+        Err(e) => assert!(
+            matches!(e.sys().and_then(|e| e.errno()), Some(libc::EACCES)),
+            "{}",
+            e
+        ),
+    }
+}
+
+#[test]
+fn inherit_stat_test() {
+    let specs: Vec<EventSpec> = vec![
+        EventSpec::from(StatEvent::TaskClock),
+        EventSpec::from(StatEvent::ContextSwitches),
+    ];
+    // Inherited events can only be mapped one CPU at a time, and
+    // the kernel only writes an exiting thread's counts for events
+    // without siblings, so each event is opened alone on each CPU.
+    // Sampling reads per thread keeps the kernel from swapping the
+    // thread's counters with ours as they take turns on a CPU.
+    let mut groups = Vec::new();
+    let mut rings = Vec::new();
+    for cpu in online_cpus().unwrap() {
+        for spec in &specs {
+            let mut spec = spec.clone().inherit(true);
+            spec.inherit_stat = true;
+            spec.sample_type = perf_event_sample_format_PERF_SAMPLE_READ
+                | perf_event_sample_format_PERF_SAMPLE_TID;
+            let group = match EventGroup::on_cpu(&[spec], None, cpu) {
+                Ok(group) => group,
+                // Sampling reads of inherited events needs Linux 6.12.
+                Err(e) if matches!(e.sys().and_then(|e| e.errno()), Some(libc::EINVAL)) => return,
+                Err(e) => panic!("{}", e),
+            };
+            rings.push(RingBuffer::new(&group.leader().fd, 8).unwrap());
+            groups.push(group);
+        }
+    }
+    for group in &groups {
+        group.start_counter().unwrap();
+    }
+    std::thread::spawn(|| {
+        let start = std::time::Instant::now();
+        while start.elapsed().as_millis() < 5 {}
+    })
+    .join()
+    .unwrap();
+    let mut total = 0;
+    for group in groups.iter().step_by(specs.len()) {
+        total += group.stop_counter().unwrap().value();
+    }
+    let mut reads = 0;
+    let mut thread = 0;
+    for ring in rings.iter_mut() {
+        for record in ring.records() {
+            if let Record::Read { tid, reading, .. } = record {
+                assert_ne!(tid as i32, unsafe { libc::gettid() });
+                reads += 1;
+                // Only the task-clock groups, the first on each CPU, count time.
+                for group in groups.iter().step_by(specs.len()) {
+                    if let Some(reading) = group.member_order(reading.clone()) {
+                        thread += reading.value();
+                    }
+                }
+            }
+        }
+    }
+    assert!(reads >= specs.len(), "{} PERF_RECORD_READs", reads);
+    assert!(thread >= 1_000_000, "thread counted {}ns", thread);
+    assert!(total >= thread);
+}
+
+#[test]
+fn empty_group_test() {
+    assert!(matches!(
+        EventGroup::new(&[], None),
+        Err(EventErr::InvalidEvent)
+    ));
+}
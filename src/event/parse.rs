@@ -0,0 +1,492 @@
+//! A parser for perf's event syntax, so that
+//! perf command lines can be reused with ruperf:
+//!
+//! ```text
+//! events   := group (',' group)*
+//! group    := '{' event (',' event)* '}' [':' modifiers] | event
+//! event    := name [':' modifiers]
+//!           | system ':' tracepoint [':' modifiers]
+//!           | 'mem:' number ['/' number] [':' access] [':' modifiers]
+//!           | pmu '/' [term (',' term)*] '/' [[':'] modifiers]
+//! term     := name ['=' number]
+//! modifiers:= ('u' | 'k' | 'h' | 'p')+
+//! access   := ('r' | 'w' | 'x')+
+//! ```
+//!
+//! A name is a hardware, software or cache event
+//! (`cycles`, `L1-dcache-load-misses`), or a raw
+//! event code `rNNN` given in hex. A tracepoint
+//! is named by its tracefs directories, e.g. `sched:sched_switch`.
+//! A `mem:` event is a hardware breakpoint on an address,
+//! by default a 4 byte read or write, like perf's.
+//! A name may also be a vendor event from the running CPU's
+//! pmu-events tables, e.g. `inst_retired.any`.
+
+use crate::bindings::*;
+use crate::event::names;
+use crate::event::pmu::{self, Pmu, EVENT_SOURCE};
+use crate::event::pmu_events::PmuEvents;
+use crate::event::spec::EventSpec;
+use crate::event::tracepoint::{self, Tracepoint};
+use crate::event::utils::SyntaxErr;
+use std::path::Path;
+
+/// Parse a comma separated list of events and groups.
+/// Every item is returned as a group; a lone event is
+/// a group of one.
+pub fn parse_events(input: &str) -> Result<Vec<Vec<EventSpec>>, SyntaxErr> {
+    let tracefs = tracepoint::tracefs_events();
+    parse(
+        input,
+        Path::new(EVENT_SOURCE),
+        tracefs.as_deref(),
+        &PmuEvents::for_this_cpu,
+    )
+}
+
+/// Parse `input`, looking PMUs up under `pmus`, tracepoints
+/// under `tracefs`, if it's mounted, and vendor events in
+/// the tables `vendor` loads, if a name is none of the others.
+fn parse(
+    input: &str,
+    pmus: &Path,
+    tracefs: Option<&Path>,
+    vendor: &dyn Fn() -> Option<&'static PmuEvents>,
+) -> Result<Vec<Vec<EventSpec>>, SyntaxErr> {
+    let mut parser = Parser {
+        input,
+        pos: 0,
+        pmus,
+        tracefs,
+        vendor,
+    };
+    let mut groups = vec![parser.group()?];
+    while parser.eat(',') {
+        groups.push(parser.group()?);
+    }
+    match parser.peek() {
+        Some(c) => Err(parser.err(parser.pos, format!("unexpected '{}'", c))),
+        None => Ok(groups),
+    }
+}
+
+/// Which privilege levels to count, and how precise to be.
+#[derive(Default)]
+struct Modifiers {
+    user: bool,
+    kernel: bool,
+    hv: bool,
+    precise: u8,
+}
+
+impl Modifiers {
+    /// Like perf, naming any privilege level
+    /// excludes all of the others.
+    fn apply(&self, spec: &mut EventSpec) {
+        if self.user || self.kernel || self.hv {
+            spec.exclude_user = !self.user;
+            spec.exclude_kernel = !self.kernel;
+            spec.exclude_hv = !self.hv;
+        }
+        spec.precise_ip = spec.precise_ip.max(self.precise);
+    }
+}
+
+/// Recursive descent over `input`, where `pos`
+/// is a byte offset into it.
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    /// The sysfs directory PMUs are listed in.
+    pmus: &'a Path,
+    /// The tracefs `events` directory.
+    tracefs: Option<&'a Path>,
+    /// Loads this CPU's pmu-events tables, which are
+    /// only needed for names that aren't otherwise known.
+    vendor: &'a dyn Fn() -> Option<&'static PmuEvents>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            return true;
+        }
+        false
+    }
+    fn expect(&mut self, c: char) -> Result<(), SyntaxErr> {
+        if self.eat(c) {
+            return Ok(());
+        }
+        let found = match self.peek() {
+            Some(f) => format!("'{}'", f),
+            None => "end of input".to_string(),
+        };
+        Err(self.err(self.pos, format!("expected '{}', found {}", c, found)))
+    }
+    /// An error pointing at byte offset `pos`.
+    fn err(&self, pos: usize, msg: String) -> SyntaxErr {
+        SyntaxErr {
+            column: self.input[..pos].chars().count() + 1,
+            msg,
+        }
+    }
+    /// An event, PMU or term name.
+    fn name(&mut self) -> Result<&'a str, SyntaxErr> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if !(c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+                break;
+            }
+            self.pos += 1;
+        }
+        if self.pos == start {
+            let msg = match self.peek() {
+                Some(c) => format!("expected an event name, found '{}'", c),
+                None => "expected an event name".to_string(),
+            };
+            return Err(self.err(start, msg));
+        }
+        Ok(&self.input[start..self.pos])
+    }
+    /// A decimal, or `0x` prefixed hex, number.
+    fn number(&mut self) -> Result<u64, SyntaxErr> {
+        let start = self.pos;
+        let text = self.name()?;
+        pmu::parse_number(text).ok_or_else(|| self.err(start, format!("invalid number '{}'", text)))
+    }
+    fn group(&mut self) -> Result<Vec<EventSpec>, SyntaxErr> {
+        if !self.eat('{') {
+            return Ok(vec![self.event()?]);
+        }
+        let mut events = vec![self.event()?];
+        while self.eat(',') {
+            events.push(self.event()?);
+        }
+        self.expect('}')?;
+        // Group modifiers apply to every member,
+        // on top of the member's own.
+        if self.eat(':') {
+            let start = self.pos;
+            let modifiers = self.modifiers()?;
+            let text = &self.input[start..self.pos];
+            for event in &mut events {
+                modifiers.apply(event);
+                event.name = format!("{}:{}", event.name, text);
+            }
+        }
+        Ok(events)
+    }
+    fn event(&mut self) -> Result<EventSpec, SyntaxErr> {
+        let start = self.pos;
+        let name = self.name()?;
+        let pmu = self.eat('/');
+        let mut spec = if pmu {
+            self.pmu_event(name, start)?
+        } else if name == "mem" && self.peek() == Some(':') {
+            self.breakpoint()?
+        } else if let Some(root) = self.system(name) {
+            self.tracepoint(root, name, start)?
+        } else {
+            self.named_event(name, start)?
+        };
+        // Like perf, PMU events take modifiers
+        // straight after the closing `/`.
+        let bare = pmu && matches!(self.peek(), Some(c) if c.is_ascii_alphabetic());
+        if self.eat(':') || bare {
+            self.modifiers()?.apply(&mut spec);
+        }
+        spec.name = self.input[start..self.pos].to_string();
+        Ok(spec)
+    }
+    /// A symbolic or raw event, found at `start`.
+    fn named_event(&self, name: &str, start: usize) -> Result<EventSpec, SyntaxErr> {
+        if let Some(spec) = names::lookup(name) {
+            return Ok(spec);
+        }
+        if let Some(spec) = (self.vendor)().and_then(|v| v.event(name)) {
+            return Ok(spec);
+        }
+        match name
+            .strip_prefix('r')
+            .map(|hex| u64::from_str_radix(hex, 16))
+        {
+            Some(Ok(config)) => Ok(EventSpec::raw(config)),
+            _ => Err(self.err(start, format!("unknown event '{}'", name))),
+        }
+    }
+    /// The tracefs `events` directory if `name`, followed by
+    /// a `:`, is a tracepoint system rather than an event with modifiers.
+    fn system(&self, name: &str) -> Option<&'a Path> {
+        let root = self.tracefs?;
+        if self.peek() == Some(':') && root.join(name).is_dir() {
+            return Some(root);
+        }
+        None
+    }
+    /// The tracepoint in `system`, just before the `:`
+    /// that separates the two.
+    fn tracepoint(
+        &mut self,
+        root: &Path,
+        system: &str,
+        start: usize,
+    ) -> Result<EventSpec, SyntaxErr> {
+        self.expect(':')?;
+        let name = self.name()?;
+        match Tracepoint::open(root, system, name) {
+            Some(tracepoint) => Ok(tracepoint.spec()),
+            None => Err(self.err(start, format!("unknown tracepoint '{}:{}'", system, name))),
+        }
+    }
+    /// The `:0xADDR[/len][:rwx]` of a `mem:` event.
+    fn breakpoint(&mut self) -> Result<EventSpec, SyntaxErr> {
+        self.expect(':')?;
+        let addr = self.number()?;
+        let mut len = None;
+        if self.eat('/') {
+            let start = self.pos;
+            len = Some(self.number()?);
+            if !matches!(len, Some(1) | Some(2) | Some(4) | Some(8)) {
+                let msg = "breakpoint length must be 1, 2, 4 or 8".to_string();
+                return Err(self.err(start, msg));
+            }
+        }
+        let mut bp_type = HW_BREAKPOINT_EMPTY;
+        if self.eat(':') {
+            let start = self.pos;
+            while let Some(c) = self.peek() {
+                bp_type |= match c {
+                    'r' => HW_BREAKPOINT_R,
+                    'w' => HW_BREAKPOINT_W,
+                    'x' => HW_BREAKPOINT_X,
+                    ':' | ',' | '}' => break,
+                    _ => return Err(self.err(self.pos, format!("unknown access '{}'", c))),
+                };
+                self.pos += 1;
+            }
+            if self.pos == start {
+                return Err(self.err(start, "expected an access, one of 'rwx'".to_string()));
+            }
+            // The kernel can't watch for execution
+            // and data accesses with one breakpoint.
+            if bp_type & HW_BREAKPOINT_X != 0 && bp_type != HW_BREAKPOINT_X {
+                let msg = "'x' can't be combined with 'r' or 'w'".to_string();
+                return Err(self.err(start, msg));
+            }
+        }
+        if bp_type == HW_BREAKPOINT_EMPTY {
+            bp_type = HW_BREAKPOINT_RW;
+        }
+        // Instructions are watched a word at a time.
+        let len = match len {
+            Some(len) => len,
+            None if bp_type == HW_BREAKPOINT_X => std::mem::size_of::<libc::c_long>() as u64,
+            None => HW_BREAKPOINT_LEN_4 as u64,
+        };
+        Ok(EventSpec::breakpoint(addr, len, bp_type))
+    }
+    /// The `term=value,...` list of a PMU event,
+    /// just after its opening `/`. A term without a value
+    /// is either a flag, set to 1, or one of the PMU's named events.
+    fn pmu_event(&mut self, name: &str, start: usize) -> Result<EventSpec, SyntaxErr> {
+        let pmu = match Pmu::open(self.pmus, name) {
+            Some(pmu) => pmu,
+            None => return Err(self.err(start, format!("unknown PMU '{}'", name))),
+        };
+        let mut spec = pmu.spec();
+        if self.eat('/') {
+            return Ok(spec);
+        }
+        loop {
+            let term_start = self.pos;
+            let term = self.name()?;
+            let set = if self.eat('=') {
+                let value = self.number()?;
+                pmu.set_term(&mut spec, term, value)
+            } else {
+                match pmu.set_event(&mut spec, term) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => pmu.set_term(&mut spec, term, 1),
+                    Err(msg) => Err(msg),
+                }
+            };
+            if let Err(msg) = set {
+                return Err(self.err(term_start, msg));
+            }
+            if !self.eat(',') {
+                break;
+            }
+        }
+        self.expect('/')?;
+        Ok(spec)
+    }
+    fn modifiers(&mut self) -> Result<Modifiers, SyntaxErr> {
+        let mut modifiers = Modifiers::default();
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            match c {
+                'u' => modifiers.user = true,
+                'k' => modifiers.kernel = true,
+                'h' => modifiers.hv = true,
+                'p' if modifiers.precise < 3 => modifiers.precise += 1,
+                'p' => return Err(self.err(self.pos, "at most 3 'p' modifiers".to_string())),
+                ',' | '}' => break,
+                _ => return Err(self.err(self.pos, format!("unknown modifier '{}'", c))),
+            }
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return Err(self.err(start, "expected a modifier".to_string()));
+        }
+        Ok(modifiers)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn parse_modifiers_test() {
+    let groups = parse_events("cycles:u,instructions:k,cycles:ppp").unwrap();
+    assert_eq!(groups.len(), 3);
+    let user = &groups[0][0];
+    assert_eq!(user.name, "cycles:u");
+    assert!(!user.exclude_user && user.exclude_kernel && user.exclude_hv);
+    let kernel = &groups[1][0];
+    assert!(kernel.exclude_user && !kernel.exclude_kernel && kernel.exclude_hv);
+    assert_eq!(groups[2][0].precise_ip, 3);
+}
+
+#[test]
+fn parse_groups_test() {
+    let groups = parse_events("{cycles,instructions}:u,task-clock").unwrap();
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].len(), 2);
+    assert_eq!(groups[0][1].name, "instructions:u");
+    assert!(groups[0][1].exclude_kernel);
+    assert_eq!(groups[1][0].name, "task-clock");
+}
+
+#[test]
+fn parse_raw_and_pmu_test() {
+    use crate::event::utils::fixture;
+    let pmus = fixture("sysfs/bus/event_source/devices");
+    let input = "r1a8,cpu/event=0x3c,umask=0x00,inv,cmask=2/k";
+    let groups = parse(input, &pmus, None, &|| None).unwrap();
+    assert_eq!(groups[0][0].type_, perf_type_id_PERF_TYPE_RAW);
+    assert_eq!(groups[0][0].config, 0x1a8);
+    let pmu = &groups[1][0];
+    assert_eq!(pmu.type_, 4);
+    assert_eq!(pmu.config, 0x0280_003c);
+    assert_eq!(pmu.name, "cpu/event=0x3c,umask=0x00,inv,cmask=2/k");
+    assert!(!pmu.exclude_kernel);
+    let cache = &parse_events("L1-dcache-load-misses").unwrap()[0][0];
+    assert_eq!(cache.type_, perf_type_id_PERF_TYPE_HW_CACHE);
+}
+
+#[test]
+fn parse_error_test() {
+    let err = |input| parse_events(input).unwrap_err();
+    assert_eq!(err("cycles,bogus").column, 8);
+    assert_eq!(err("cycles:x").column, 8);
+    assert_eq!(err("cycles:pppp").column, 11);
+    assert_eq!(err("{cycles,instructions").column, 21);
+    assert_eq!(err("nopmu/config=1/").column, 1);
+    assert_eq!(err("cycles,").column, 8);
+    assert_eq!(err("cycles)").msg, "unexpected ')'");
+}
+
+#[test]
+fn parse_pmu_error_test() {
+    use crate::event::utils::fixture;
+    let pmus = fixture("sysfs/bus/event_source/devices");
+    let err = |input| parse(input, &pmus, None, &|| None).unwrap_err();
+    assert_eq!(err("cpu/bogus=1/").column, 5);
+    assert_eq!(err("cpu/event=zz/").column, 11);
+    assert_eq!(err("cycles,cpu/event=0x3c,umask=0x100/").column, 23);
+    assert_eq!(err("msr/tsc").msg, "expected '/', found end of input");
+}
+
+#[test]
+fn parse_pmu_events_test() {
+    use crate::event::utils::fixture;
+    let groups = parse(
+        "msr/tsc/,cpu/mem-loads,ldlat=8/u,cpu/cycles-t,config2=5/",
+        &fixture("sysfs/bus/event_source/devices"),
+        None,
+        &|| None,
+    )
+    .unwrap();
+    assert_eq!(groups[0][0].type_, 12);
+    assert_eq!(groups[0][0].config, 0);
+    let loads = &groups[1][0];
+    assert_eq!(loads.config, 0x01cd);
+    assert_eq!(loads.config1, 8);
+    assert!(loads.exclude_kernel);
+    assert_eq!(groups[2][0].config, 0x1_0000_003c);
+    assert_eq!(groups[2][0].config2, 5);
+}
+
+#[test]
+fn parse_tracepoint_test() {
+    use crate::event::utils::fixture;
+    let pmus = fixture("sysfs/bus/event_source/devices");
+    let tracefs = fixture("tracefs/events");
+    let parse = |input| parse(input, &pmus, Some(&tracefs), &|| None);
+    let groups = parse("sched:sched_switch,{syscalls:sys_enter_openat,cycles:u}").unwrap();
+    let switch = &groups[0][0];
+    assert_eq!(switch.type_, perf_type_id_PERF_TYPE_TRACEPOINT);
+    assert_eq!(switch.config, 316);
+    assert_eq!(switch.name, "sched:sched_switch");
+    assert_eq!(groups[1][0].config, 622);
+    assert_eq!(groups[1][1].name, "cycles:u");
+    let err = parse("cycles,sched:bogus").unwrap_err();
+    assert_eq!(err.column, 8);
+    assert_eq!(err.msg, "unknown tracepoint 'sched:bogus'");
+    assert_eq!(parse("sched:").unwrap_err().column, 7);
+}
+
+#[test]
+fn parse_breakpoint_test() {
+    let groups = parse_events("mem:0x1000,mem:0x2000/8:w,mem:0x3000:x:k").unwrap();
+    let rw = &groups[0][0];
+    assert_eq!(rw.type_, perf_type_id_PERF_TYPE_BREAKPOINT);
+    assert_eq!((rw.config1, rw.config2), (0x1000, 4));
+    assert_eq!(rw.bp_type, HW_BREAKPOINT_RW);
+    assert_eq!(rw.name, "mem:0x1000");
+    let w = &groups[1][0];
+    assert_eq!((w.config1, w.config2), (0x2000, 8));
+    assert_eq!(w.bp_type, HW_BREAKPOINT_W);
+    let x = &groups[2][0];
+    assert_eq!(x.bp_type, HW_BREAKPOINT_X);
+    assert_eq!(x.config2, std::mem::size_of::<libc::c_long>() as u64);
+    assert!(x.exclude_user && !x.exclude_kernel);
+    let err = |input| parse_events(input).unwrap_err();
+    assert_eq!(err("mem:0x1000/3").column, 12);
+    assert_eq!(err("mem:0x1000:rq").msg, "unknown access 'q'");
+    assert_eq!(err("mem:0x1000:rx").column, 12);
+    assert_eq!(err("mem:zz").column, 5);
+}
+
+#[test]
+fn parse_vendor_events_test() {
+    use crate::event::utils::fixture;
+    let pmus = fixture("sysfs/bus/event_source/devices");
+    let vendor = PmuEvents::load(&fixture("pmu-events/x86"), "GenuineIntel-6-97-2").unwrap();
+    let vendor: &'static PmuEvents = Box::leak(Box::new(vendor));
+    let parse = |input| parse(input, &pmus, None, &|| Some(vendor));
+    let groups = parse("{inst_retired.any,CPU_CLK_UNHALTED.THREAD:k},cycles").unwrap();
+    let inst = &groups[0][0];
+    assert_eq!(inst.type_, perf_type_id_PERF_TYPE_RAW);
+    assert_eq!(inst.config, 0xc0);
+    assert_eq!(inst.name, "inst_retired.any");
+    assert_eq!(groups[0][1].config, 0x3c);
+    assert!(groups[0][1].exclude_user);
+    assert_eq!(groups[1][0].type_, perf_type_id_PERF_TYPE_HARDWARE);
+    assert_eq!(
+        parse("inst_retired.bogus").unwrap_err().msg,
+        "unknown event 'inst_retired.bogus'"
+    );
+}
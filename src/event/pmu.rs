@@ -0,0 +1,285 @@
+//! Dynamic PMUs, as the kernel describes them in sysfs.
+//! Every PMU has a directory under `EVENT_SOURCE` with:
+//! - `type`, the `perf_event_attr.type` to open its events with,
+//! - `format/<term>`, where in `config`, `config1` or `config2`
+//!   each term's bits go, e.g. `config:0-7` or `config1:0-15`,
+//! - `events/<name>`, named events as a list of terms,
+//!   e.g. `event=0x3c,umask=0x00`, and `events/<name>.scale`,
+//!   what to multiply the event's count by.
+//!
+//! This is what lets `pmu/term=value,.../` and `pmu/event/`
+//! events be turned into an `EventSpec`.
+
+use crate::event::spec::EventSpec;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Where the kernel lists its PMUs.
+pub const EVENT_SOURCE: &str = "/sys/bus/event_source/devices";
+
+/// Which `perf_event_attr` field a format term is packed into.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Field {
+    Config,
+    Config1,
+    Config2,
+}
+
+/// Where a term's value goes. The value's bits fill
+/// each inclusive range of bits in turn, lowest first.
+#[derive(Debug, Clone, PartialEq)]
+struct Format {
+    field: Field,
+    bits: Vec<(u32, u32)>,
+}
+
+impl Format {
+    /// Parse a format file, e.g. `config:0-7,32-35`.
+    fn parse(s: &str) -> Option<Self> {
+        let (field, ranges) = s.trim().split_once(':')?;
+        let field = match field {
+            "config" => Field::Config,
+            "config1" => Field::Config1,
+            "config2" => Field::Config2,
+            _ => return None,
+        };
+        let mut bits = Vec::new();
+        for range in ranges.split(',') {
+            let (lo, hi) = match range.split_once('-') {
+                Some((lo, hi)) => (lo.parse().ok()?, hi.parse().ok()?),
+                None => {
+                    let bit = range.parse().ok()?;
+                    (bit, bit)
+                }
+            };
+            if lo > hi || hi > 63 {
+                return None;
+            }
+            bits.push((lo, hi));
+        }
+        Some(Format { field, bits })
+    }
+    /// Total number of bits the term can hold.
+    fn width(&self) -> u32 {
+        self.bits.iter().map(|(lo, hi)| hi - lo + 1).sum()
+    }
+    /// Scatter `value` into `spec`, or fail if it doesn't fit.
+    fn set(&self, spec: &mut EventSpec, mut value: u64) -> Result<(), String> {
+        if self.width() < 64 && value >> self.width() != 0 {
+            return Err(format!(
+                "value {:#x} does not fit in {} bits",
+                value,
+                self.width()
+            ));
+        }
+        let config = match self.field {
+            Field::Config => &mut spec.config,
+            Field::Config1 => &mut spec.config1,
+            Field::Config2 => &mut spec.config2,
+        };
+        for (lo, hi) in &self.bits {
+            let width = hi - lo + 1;
+            let mask = if width == 64 {
+                u64::MAX
+            } else {
+                (1 << width) - 1
+            };
+            *config = (*config & !(mask << lo)) | ((value & mask) << lo);
+            value = value.checked_shr(width).unwrap_or(0);
+        }
+        Ok(())
+    }
+}
+
+/// A PMU the kernel registered, read from sysfs.
+#[derive(Debug, Clone)]
+pub struct Pmu {
+    pub name: String,
+    pub type_: u32,
+    formats: BTreeMap<String, Format>,
+    /// Named events and the terms they stand for.
+    pub events: BTreeMap<String, String>,
+    /// What to multiply the counts of named events by, if not 1.
+    pub scales: BTreeMap<String, f64>,
+}
+
+impl Pmu {
+    /// Read the PMU `name` from the `devices` directory
+    /// `root`, or `None` if there's no such PMU.
+    pub fn open(root: &Path, name: &str) -> Option<Self> {
+        let dir = root.join(name);
+        let type_ = fs::read_to_string(dir.join("type")).ok()?;
+        let mut formats = BTreeMap::new();
+        for (term, contents) in read_files(&dir.join("format")) {
+            if let Some(format) = Format::parse(&contents) {
+                formats.insert(term, format);
+            }
+        }
+        // `<event>.scale`, `.unit` and friends describe the
+        // event of the same name, they aren't events themselves.
+        let mut events = BTreeMap::new();
+        let mut scales = BTreeMap::new();
+        for (event, contents) in read_files(&dir.join("events")) {
+            match event.split_once('.') {
+                None => {
+                    events.insert(event, contents.trim().to_string());
+                }
+                Some((event, "scale")) => {
+                    if let Ok(scale) = contents.trim().parse() {
+                        scales.insert(event.to_string(), scale);
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        Some(Pmu {
+            name: name.to_string(),
+            type_: type_.trim().parse().ok()?,
+            formats,
+            events,
+            scales,
+        })
+    }
+    /// Every PMU under `root`, sorted by name.
+    pub fn all(root: &Path) -> Vec<Self> {
+        let mut pmus: Vec<Self> = match fs::read_dir(root) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| Pmu::open(root, &e.file_name().to_string_lossy()))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        pmus.sort_by(|a, b| a.name.cmp(&b.name));
+        pmus
+    }
+    /// A spec for an event on this PMU, to have terms set on it.
+    /// Many PMUs outside the core, like `msr`, can't tell user
+    /// from kernel space and reject events that try to, so
+    /// unlike other events these count everything by default.
+    pub fn spec(&self) -> EventSpec {
+        EventSpec::new(self.type_, 0)
+            .exclude_kernel(false)
+            .exclude_hv(false)
+    }
+    /// Set `term` to `value` in `spec`. The raw `config`
+    /// fields may always be set; other terms must
+    /// be listed in the PMU's `format` directory.
+    pub fn set_term(&self, spec: &mut EventSpec, term: &str, value: u64) -> Result<(), String> {
+        match term {
+            "config" => spec.config = value,
+            "config1" => spec.config1 = value,
+            "config2" => spec.config2 = value,
+            _ => match self.formats.get(term) {
+                Some(format) => format.set(spec, value)?,
+                None => return Err(format!("unknown term '{}' for PMU '{}'", term, self.name)),
+            },
+        }
+        Ok(())
+    }
+    /// Set all the terms the named `event` stands for,
+    /// or return false if there's no such event.
+    pub fn set_event(&self, spec: &mut EventSpec, event: &str) -> Result<bool, String> {
+        let terms = match self.events.get(event) {
+            Some(terms) => terms,
+            None => return Ok(false),
+        };
+        for term in terms.split(',').filter(|t| !t.is_empty()) {
+            let (term, value) = match term.split_once('=') {
+                Some((term, value)) => (term, value),
+                None => (term, "1"),
+            };
+            // Terms like `period` are settings for
+            // sampling, not part of the event.
+            if !self.formats.contains_key(term) && !term.starts_with("config") {
+                continue;
+            }
+            let value = parse_number(value)
+                .ok_or_else(|| format!("bad value '{}' in {}/{}/", value, self.name, event))?;
+            self.set_term(spec, term, value)?;
+        }
+        Ok(true)
+    }
+    /// The spec for the named `event`, called `pmu/event/`.
+    pub fn event(&self, event: &str) -> Option<EventSpec> {
+        let mut spec = self.spec();
+        match self.set_event(&mut spec, event) {
+            Ok(true) => Some(spec.name(&format!("{}/{}/", self.name, event))),
+            _ => None,
+        }
+    }
+}
+
+/// A decimal, or `0x` prefixed hex, number.
+pub fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// The name and contents of every file in `dir`.
+fn read_files(dir: &Path) -> Vec<(String, String)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let contents = fs::read_to_string(e.path()).ok()?;
+            Some((e.file_name().to_string_lossy().into_owned(), contents))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn format_test() {
+    let format = Format::parse("config:0-7,32-35\n").unwrap();
+    assert_eq!(format.field, Field::Config);
+    assert_eq!(format.width(), 12);
+    let mut spec = EventSpec::new(0, 0);
+    format.set(&mut spec, 0x5ab).unwrap();
+    assert_eq!(spec.config, 0x5_0000_00ab);
+    assert!(format.set(&mut spec, 0x1000).is_err());
+    assert_eq!(Format::parse("config1:21").unwrap().bits, vec![(21, 21)]);
+    assert!(Format::parse("config3:0-7").is_none());
+    assert!(Format::parse("config:7-0").is_none());
+}
+
+#[test]
+fn fixture_pmus_test() {
+    use crate::event::utils::fixture;
+    let pmus = Pmu::all(&fixture("sysfs/bus/event_source/devices"));
+    let names: Vec<&str> = pmus.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, vec!["amd_df", "cpu", "msr"]);
+    let cpu = &pmus[1];
+    assert_eq!(cpu.type_, 4);
+    assert!(cpu.events.contains_key("topdown-total-slots"));
+    assert!(!cpu.events.contains_key("topdown-total-slots.scale"));
+    assert_eq!(cpu.scales.get("topdown-total-slots"), Some(&2.0));
+    let df = &pmus[0];
+    let mut spec = df.spec();
+    df.set_term(&mut spec, "event", 0x3ff).unwrap();
+    df.set_term(&mut spec, "umask", 0x1).unwrap();
+    assert_eq!(spec.config, 0x3_0000_01ff);
+}
+
+#[test]
+fn fixture_events_test() {
+    use crate::event::utils::fixture;
+    let pmus = fixture("sysfs/bus/event_source/devices");
+    let cpu = Pmu::open(&pmus, "cpu").unwrap();
+    let spec = cpu.event("cycles-t").unwrap();
+    assert_eq!(spec.name, "cpu/cycles-t/");
+    assert_eq!(spec.type_, 4);
+    assert_eq!(spec.config, 0x1_0000_003c);
+    let spec = cpu.event("mem-loads").unwrap();
+    assert_eq!(spec.config, 0x01cd);
+    assert_eq!(spec.config1, 3);
+    assert!(cpu.event("bogus").is_none());
+    let err = cpu.set_term(&mut cpu.spec(), "bogus", 1).unwrap_err();
+    assert_eq!(err, "unknown term 'bogus' for PMU 'cpu'");
+    assert!(Pmu::open(&pmus, "nope").is_none());
+}
@@ -0,0 +1,368 @@
+//! Vendor event and metric tables, in the JSON format of the
+//! Linux tree's `tools/perf/pmu-events/arch`. A table directory,
+//! like `arch/x86`, has a `mapfile.csv` whose lines
+//! `Family-model,Version,Filename,EventType` map a regex over
+//! the CPU's id to the directory holding that CPU's JSON files:
+//!
+//! ```text
+//! Family-model,Version,Filename,EventType
+//! GenuineIntel-6-(97|9A),v1.24,alderlake,core
+//! ```
+//!
+//! Each JSON file is an array of events, named by `EventName`,
+//! or metrics, named by `MetricName`. Only core events are
+//! loaded; uncore events, which have a `Unit`, and events
+//! defined elsewhere, like Arm's `ArchStdEvent`s, are skipped.
+
+use crate::event::pmu::parse_number;
+use crate::event::spec::EventSpec;
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Set to a table directory, or to the `arch` directory
+/// above them, to load tables from there.
+pub const PMU_EVENTS_VAR: &str = "RUPERF_PMU_EVENTS";
+
+/// Where tables are looked for if `PMU_EVENTS_VAR` isn't set.
+pub const PMU_EVENTS: &[&str] = &[
+    "/usr/local/share/ruperf/pmu-events",
+    "/usr/share/ruperf/pmu-events",
+];
+
+/// The table directory for this architecture, which perf calls
+/// e.g. `arch/x86`, under `root`, or `root` itself if it is one.
+fn table_dir(root: &Path) -> Option<PathBuf> {
+    let arch = match std::env::consts::ARCH {
+        "x86" | "x86_64" => "x86",
+        "aarch64" => "arm64",
+        arch => arch,
+    };
+    [root.to_path_buf(), root.join(arch)]
+        .iter()
+        .find(|dir| dir.join("mapfile.csv").is_file())
+        .cloned()
+}
+
+/// The id the mapfile is matched against, from `/proc/cpuinfo`:
+/// `vendor-family-model-stepping` on x86, with the model and
+/// stepping in hex, e.g. `GenuineIntel-6-9A-3`, or the MIDR
+/// on Arm, e.g. `0x00000000410fd0c0`.
+pub fn cpuid(cpuinfo: &str) -> Option<String> {
+    // Every CPU is assumed to be the same as the first.
+    let first = cpuinfo.split("\n\n").next()?;
+    let field = |name: &str| {
+        first.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.trim() == name {
+                Some(value.trim())
+            } else {
+                None
+            }
+        })
+    };
+    let number = |name: &str| -> Option<u64> {
+        let value = field(name)?;
+        match value.strip_prefix("0x") {
+            Some(_) => parse_number(value),
+            None => value.parse().ok(),
+        }
+    };
+    if let Some(vendor) = field("vendor_id") {
+        return Some(format!(
+            "{}-{}-{:X}-{:X}",
+            vendor,
+            number("cpu family")?,
+            number("model")?,
+            number("stepping")?
+        ));
+    }
+    let midr = number("CPU implementer")? << 24
+        | number("CPU variant")? << 20
+        | 0xf << 16
+        | number("CPU part")? << 4
+        | number("CPU revision")?;
+    Some(format!("{:#018x}", midr))
+}
+
+/// Does the mapfile regex `pattern` match all of `cpuid`? Like
+/// perf, a pattern without a stepping matches any stepping.
+fn matches_cpuid(pattern: &str, cpuid: &str) -> bool {
+    let fields = pattern.matches('-').count() + 1;
+    let cpuid = if fields < 4 {
+        cpuid
+            .splitn(fields + 1, '-')
+            .take(fields)
+            .collect::<Vec<_>>()
+            .join("-")
+    } else {
+        cpuid.to_string()
+    };
+    // Mapfile patterns are POSIX extended regexes, which
+    // the regex crate's syntax covers, classes included.
+    match Regex::new(&format!("^(?:{})$", pattern)) {
+        Ok(re) => re.is_match(&cpuid),
+        Err(_) => false,
+    }
+}
+
+/// A named core event from the tables.
+#[derive(Debug, Clone)]
+pub struct VendorEvent {
+    pub name: String,
+    pub spec: EventSpec,
+    pub description: String,
+}
+
+/// A metric from the tables, an expression over events
+/// and other metrics, e.g. `INST_RETIRED.ANY / CPU_CLK_UNHALTED.THREAD`.
+#[derive(Debug, Clone)]
+pub struct VendorMetric {
+    pub name: String,
+    pub expr: String,
+    /// The `MetricGroup`s it's in.
+    pub groups: Vec<String>,
+    /// What to scale it by and its unit, e.g. `100%`.
+    pub scale_unit: Option<String>,
+    pub description: String,
+}
+
+/// The events and metrics of the running CPU's model.
+#[derive(Debug, Default)]
+pub struct PmuEvents {
+    pub events: Vec<VendorEvent>,
+    pub metrics: Vec<VendorMetric>,
+}
+
+impl PmuEvents {
+    /// The tables for this CPU, from `PMU_EVENTS_VAR` or `PMU_EVENTS`,
+    /// or `None` if there are none for it or it can't be identified.
+    /// They're loaded the first time they're asked for, then kept.
+    pub fn for_this_cpu() -> Option<&'static Self> {
+        static TABLES: OnceLock<Option<PmuEvents>> = OnceLock::new();
+        TABLES.get_or_init(Self::find).as_ref()
+    }
+    fn find() -> Option<Self> {
+        let root = match std::env::var_os(PMU_EVENTS_VAR) {
+            Some(root) => PathBuf::from(root),
+            None => PathBuf::from(PMU_EVENTS.iter().find(|p| Path::new(p).is_dir())?),
+        };
+        let cpuinfo = fs::read_to_string("/proc/cpuinfo").ok()?;
+        Self::load(&table_dir(&root)?, &cpuid(&cpuinfo)?)
+    }
+    /// Load the core tables that `dir`'s mapfile
+    /// maps `cpuid` to, or `None` if none match.
+    pub fn load(dir: &Path, cpuid: &str) -> Option<Self> {
+        let mapfile = fs::read_to_string(dir.join("mapfile.csv")).ok()?;
+        let model = mapfile.lines().find_map(|line| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            match fields.as_slice() {
+                [pattern, _, model, "core"] if matches_cpuid(pattern, cpuid) => Some(*model),
+                _ => None,
+            }
+        })?;
+        let mut tables = PmuEvents::default();
+        let mut files: Vec<PathBuf> = fs::read_dir(dir.join(model))
+            .ok()?
+            .filter_map(|e| Some(e.ok()?.path()))
+            .filter(|path| matches!(path.extension(), Some(e) if e == "json"))
+            .collect();
+        files.sort();
+        for path in files {
+            let entries: Vec<Map<String, Value>> = match fs::read_to_string(&path)
+                .ok()
+                .and_then(|json| serde_json::from_str(&json).ok())
+            {
+                Some(entries) => entries,
+                None => continue,
+            };
+            for entry in &entries {
+                if let Some(metric) = metric(entry) {
+                    tables.metrics.push(metric);
+                } else if let Some(event) = event(entry) {
+                    tables.events.push(event);
+                }
+            }
+        }
+        Some(tables)
+    }
+    /// The event called `name`, whatever its case,
+    /// as perf accepts them in lower case.
+    pub fn event(&self, name: &str) -> Option<EventSpec> {
+        let event = self
+            .events
+            .iter()
+            .find(|e| e.name.eq_ignore_ascii_case(name))?;
+        Some(event.spec.clone().name(name))
+    }
+    /// The metrics in the group, or the one metric, called `name`.
+    pub fn metric_group(&self, name: &str) -> Vec<&VendorMetric> {
+        self.metrics
+            .iter()
+            .filter(|m| {
+                m.name.eq_ignore_ascii_case(name)
+                    || m.groups.iter().any(|g| g.eq_ignore_ascii_case(name))
+            })
+            .collect()
+    }
+}
+
+/// A field, which the tables give as a string or a number.
+fn field(entry: &Map<String, Value>, key: &str) -> Option<String> {
+    match entry.get(key)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// The event an entry describes, if it's a core event.
+/// Its raw config is laid out like the x86 `cpu` PMU's format:
+/// event 0-7, umask 8-15, edge 18, any 21, inv 23, cmask 24-31,
+/// and on AMD, bits 8-11 of the 12 bit event at 32-35.
+fn event(entry: &Map<String, Value>) -> Option<VendorEvent> {
+    if entry.contains_key("Unit") {
+        return None;
+    }
+    let name = field(entry, "EventName")?;
+    let number = |key: &str| match field(entry, key) {
+        // Some events have a code for each counter, e.g. `0xB7,0xBB`.
+        Some(value) => parse_number(value.split(',').next().unwrap_or_default().trim()),
+        None => Some(0),
+    };
+    let fields = [
+        ("UMask", 8),
+        ("EdgeDetect", 18),
+        ("AnyThread", 21),
+        ("Invert", 23),
+        ("CounterMask", 24),
+    ];
+    field(entry, "EventCode")?;
+    let code = number("EventCode")?;
+    let mut config = (code & 0xff) | (code & 0xf00) << 24;
+    for (key, shift) in &fields {
+        config |= number(key)? << shift;
+    }
+    let spec = EventSpec::raw(config)
+        .config1(number("MSRValue")?)
+        .name(&name);
+    Some(VendorEvent {
+        name,
+        spec,
+        description: field(entry, "BriefDescription").unwrap_or_default(),
+    })
+}
+
+fn metric(entry: &Map<String, Value>) -> Option<VendorMetric> {
+    Some(VendorMetric {
+        name: field(entry, "MetricName")?,
+        expr: field(entry, "MetricExpr")?,
+        groups: field(entry, "MetricGroup")
+            .unwrap_or_default()
+            .split(';')
+            .filter(|g| !g.is_empty())
+            .map(str::to_string)
+            .collect(),
+        scale_unit: field(entry, "ScaleUnit"),
+        description: field(entry, "BriefDescription").unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn cpuid_test() {
+    let intel = "processor\t: 0\nvendor_id\t: GenuineIntel\ncpu family\t: 6\n\
+                 model\t\t: 154\nstepping\t: 3\n\nprocessor\t: 1\n";
+    assert_eq!(cpuid(intel).unwrap(), "GenuineIntel-6-9A-3");
+    let arm = "processor\t: 0\nBogoMIPS\t: 50.00\nCPU implementer\t: 0x41\n\
+               CPU architecture: 8\nCPU variant\t: 0x3\nCPU part\t: 0xd0c\nCPU revision\t: 1\n";
+    assert_eq!(cpuid(arm).unwrap(), "0x00000000413fd0c1");
+    assert!(cpuid("processor\t: 0\n").is_none());
+}
+
+#[test]
+fn matches_cpuid_test() {
+    assert!(matches_cpuid(
+        "GenuineIntel-6-(97|9A|B7)",
+        "GenuineIntel-6-9A-3"
+    ));
+    assert!(!matches_cpuid(
+        "GenuineIntel-6-(97|9A|B7)",
+        "GenuineIntel-6-9B-3"
+    ));
+    assert!(matches_cpuid(
+        "GenuineIntel-6-55-[01234]",
+        "GenuineIntel-6-55-4"
+    ));
+    assert!(!matches_cpuid(
+        "GenuineIntel-6-55-[01234]",
+        "GenuineIntel-6-55-7"
+    ));
+    assert!(matches_cpuid(
+        "AuthenticAMD-25-[[:xdigit:]]+",
+        "AuthenticAMD-25-A1-1"
+    ));
+    assert!(!matches_cpuid(
+        "AuthenticAMD-25-[[:xdigit:]]+",
+        "AuthenticAMD-23-31-0"
+    ));
+    assert!(matches_cpuid("GenuineIntel-6-8[CD]", "GenuineIntel-6-8D-1"));
+    assert!(matches_cpuid("0x00000000410fd0c0", "0x00000000410fd0c0"));
+    assert!(!matches_cpuid("0x00000000410fd0c0", "0x00000000410fd0c01"));
+}
+
+#[test]
+fn amd_event_test() {
+    use crate::event::utils::fixture;
+    let tables = PmuEvents::load(&fixture("pmu-events/x86"), "AuthenticAMD-25-21-0").unwrap();
+    assert_eq!(tables.event("ls_not_halted_cyc").unwrap().config, 0x76);
+    // Bits 8-11 of the event go above the counter mask, not in the umask.
+    let spec = tables
+        .event("op_cache_hit_miss.all_op_cache_accesses")
+        .unwrap();
+    assert_eq!(spec.config, 0x2_0000_078f);
+}
+
+#[test]
+fn load_test() {
+    use crate::event::utils::fixture;
+    let tables_dir = fixture("pmu-events/x86");
+    let tables = PmuEvents::load(&tables_dir, "GenuineIntel-6-9A-3").unwrap();
+    let names: Vec<&str> = tables.events.iter().map(|e| e.name.as_str()).collect();
+    // The uncore event is skipped.
+    assert_eq!(
+        names,
+        vec![
+            "CPU_CLK_UNHALTED.THREAD",
+            "INST_RETIRED.ANY",
+            "BR_MISP_RETIRED.ALL_BRANCHES",
+            "BR_INST_RETIRED.ALL_BRANCHES",
+            "OFFCORE_RESPONSE.DEMAND_DATA_RD.ANY_RESPONSE",
+            "UOPS_ISSUED.ANY_STALLS",
+        ]
+    );
+    let spec = tables.event("inst_retired.any").unwrap();
+    assert_eq!(spec.type_, crate::bindings::perf_type_id_PERF_TYPE_RAW);
+    assert_eq!(spec.config, 0x00c0);
+    assert_eq!(spec.name, "inst_retired.any");
+    let offcore = tables
+        .event("OFFCORE_RESPONSE.DEMAND_DATA_RD.ANY_RESPONSE")
+        .unwrap();
+    assert_eq!((offcore.config, offcore.config1), (0x01b7, 0x10001));
+    let stalls = tables.event("uops_issued.any_stalls").unwrap();
+    assert_eq!(stalls.config, 0x0180_010e);
+    assert!(tables.event("UNC_M_CAS_COUNT.RD").is_none());
+
+    let summary: Vec<&str> = tables
+        .metric_group("summary")
+        .iter()
+        .map(|m| m.name.as_str())
+        .collect();
+    assert_eq!(summary, vec!["IPC", "Branch_Misprediction_Ratio"]);
+    assert_eq!(tables.metric_group("CPI").len(), 1);
+    assert!(tables.metric_group("bogus").is_empty());
+
+    assert!(PmuEvents::load(&tables_dir, "HygonGenuine-24-1-0").is_none());
+}
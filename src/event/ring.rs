@@ -0,0 +1,556 @@
+//! A `RingBuffer` provides a safe interface to the
+//! memory-mapped ring buffer that the kernel writes
+//! `PERF_RECORD_*` records into for sampled events.
+//!
+//! The first page of the mapping is a `perf_event_mmap_page`
+//! describing the buffer, and the data area follows it.
+//! The kernel advances `data_head` as it writes records,
+//! and we advance `data_tail` once we have consumed them.
+//! See the "MMAP layout" section of the `perf_event_open()`
+//! man page for details.
+
+use crate::bindings::*;
+use crate::event::fd::{FileDesc, Reading};
+use crate::event::sys::wrapper::*;
+use crate::event::utils::*;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A memory-mapped perf ring buffer for a sampled event.
+/// Records are decoded using the `sample_type` and `read_format`
+/// the event was opened with. The mapping stays valid even if
+/// the `FileDesc` is dropped first, and is unmapped on drop.
+pub struct RingBuffer {
+    base: *mut u8,
+    len: usize,
+    data_offset: usize,
+    data_size: usize,
+    sample_type: u64,
+    read_format: u64,
+}
+
+/// A `PERF_RECORD_SAMPLE` record. Only the fields
+/// selected by the event's `sample_type` are set.
+#[derive(Debug, Clone, Default)]
+pub struct Sample {
+    pub misc: u16,
+    pub identifier: Option<u64>,
+    pub ip: Option<u64>,
+    pub pid: Option<u32>,
+    pub tid: Option<u32>,
+    pub time: Option<u64>,
+    pub addr: Option<u64>,
+    pub id: Option<u64>,
+    pub stream_id: Option<u64>,
+    pub cpu: Option<u32>,
+    pub period: Option<u64>,
+    pub read: Option<Reading>,
+    pub callchain: Vec<u64>,
+    pub raw: Option<Vec<u8>>,
+}
+
+/// A `PERF_RECORD_MMAP2` record, describing
+/// an executable mapping in a traced process.
+#[derive(Debug, Clone, Default)]
+pub struct Mmap2 {
+    pub pid: u32,
+    pub tid: u32,
+    pub addr: u64,
+    pub len: u64,
+    pub pgoff: u64,
+    pub maj: u32,
+    pub min: u32,
+    pub ino: u64,
+    pub ino_generation: u64,
+    pub prot: u32,
+    pub flags: u32,
+    pub filename: String,
+}
+
+/// The body shared by `PERF_RECORD_FORK`
+/// and `PERF_RECORD_EXIT` records.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Task {
+    pub pid: u32,
+    pub ppid: u32,
+    pub tid: u32,
+    pub ptid: u32,
+    pub time: u64,
+}
+
+/// A record read from the ring buffer.
+/// Records we don't decode are passed through as `Other`.
+#[derive(Debug, Clone)]
+pub enum Record {
+    Sample(Sample),
+    Mmap2(Mmap2),
+    Comm {
+        pid: u32,
+        tid: u32,
+        comm: String,
+        exec: bool,
+    },
+    Fork(Task),
+    Exit(Task),
+    /// The final counts of an inherited event, written as
+    /// its thread exits if the event was opened with `inherit_stat`.
+    Read {
+        pid: u32,
+        tid: u32,
+        reading: Reading,
+    },
+    Lost {
+        id: u64,
+        lost: u64,
+    },
+    Throttle {
+        time: u64,
+        id: u64,
+        stream_id: u64,
+    },
+    Unthrottle {
+        time: u64,
+        id: u64,
+        stream_id: u64,
+    },
+    Other {
+        type_: u32,
+        misc: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl RingBuffer {
+    /// Map a ring buffer with `pages` data pages for the
+    /// sampled event associated with `fd`.
+    /// `pages` must be a power of two.
+    pub fn new(fd: &FileDesc, pages: usize) -> Result<Self, SysErr> {
+        if !pages.is_power_of_two() {
+            return Err(SysErr::MmapFail(libc::EINVAL));
+        }
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = (pages + 1) * page_size;
+        let base = match mmap_wrap(fd.as_raw_fd(), len) {
+            Some(base) => base,
+            None => return Err(SysErr::MmapFail(errno())),
+        };
+        let page = base as *const perf_event_mmap_page;
+        // `data_offset` and `data_size` are only filled
+        // in since Linux 4.1; before that the data area
+        // always starts on the second page.
+        let (data_offset, data_size) = match unsafe { (*page).data_size } {
+            0 => (page_size, pages * page_size),
+            size => (unsafe { (*page).data_offset } as usize, size as usize),
+        };
+        Ok(Self {
+            base,
+            len,
+            data_offset,
+            data_size,
+            sample_type: fd.sample_type(),
+            read_format: fd.read_format(),
+        })
+    }
+    /// Pointer to the control page at the start of the mapping.
+    fn page(&self) -> *mut perf_event_mmap_page {
+        self.base as *mut perf_event_mmap_page
+    }
+    /// Load `data_head`. The acquire pairs with the kernel's
+    /// write barrier, so record data up to `data_head` is
+    /// visible before we read it.
+    fn head(&self) -> u64 {
+        unsafe {
+            let head = &(*self.page()).data_head as *const u64 as *const AtomicU64;
+            (*head).load(Ordering::Acquire)
+        }
+    }
+    /// Load `data_tail`. Only we write it.
+    fn tail(&self) -> u64 {
+        unsafe {
+            let tail = &(*self.page()).data_tail as *const u64 as *const AtomicU64;
+            (*tail).load(Ordering::Relaxed)
+        }
+    }
+    /// Store `data_tail`. The release makes sure we are done
+    /// reading the consumed records before the kernel may
+    /// overwrite them.
+    fn set_tail(&self, tail: u64) {
+        unsafe {
+            let ptr = &mut (*self.page()).data_tail as *mut u64 as *const AtomicU64;
+            (*ptr).store(tail, Ordering::Release);
+        }
+    }
+    /// True if there are no records waiting to be read.
+    pub fn is_empty(&self) -> bool {
+        self.head() == self.tail()
+    }
+    /// Consume and decode the next record, if there is one.
+    pub fn next_record(&mut self) -> Option<Record> {
+        let tail = self.tail();
+        let head = self.head();
+        if tail == head {
+            return None;
+        }
+        let header_size = size_of::<perf_event_header>();
+        let header = self.copy(tail, header_size);
+        let type_ = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]);
+        let misc = u16::from_ne_bytes([header[4], header[5]]);
+        let size = u16::from_ne_bytes([header[6], header[7]]) as usize;
+        if size < header_size || (head - tail) < size as u64 {
+            // A corrupt header; drop everything rather than spin.
+            self.set_tail(head);
+            return None;
+        }
+        let body = self.copy(tail + header_size as u64, size - header_size);
+        self.set_tail(tail + size as u64);
+        Some(Record::parse(
+            type_,
+            misc,
+            &body,
+            self.sample_type,
+            self.read_format,
+        ))
+    }
+    /// Iterate over the records currently in the buffer,
+    /// consuming them as they are returned.
+    pub fn records(&mut self) -> impl Iterator<Item = Record> + '_ {
+        std::iter::from_fn(move || self.next_record())
+    }
+    /// Copy `len` bytes starting at ring position `pos`.
+    fn copy(&self, pos: u64, len: usize) -> Vec<u8> {
+        let data =
+            unsafe { std::slice::from_raw_parts(self.base.add(self.data_offset), self.data_size) };
+        copy_wrapped(data, pos, len)
+    }
+}
+
+/// Unmap the buffer.
+impl Drop for RingBuffer {
+    fn drop(&mut self) {
+        munmap_wrap(self.base, self.len);
+    }
+}
+
+/// Copy `len` bytes out of the ring `data` starting at the
+/// free-running position `pos`, following the copy back round
+/// to the start of `data` if the record wraps.
+fn copy_wrapped(data: &[u8], pos: u64, len: usize) -> Vec<u8> {
+    let start = (pos % data.len() as u64) as usize;
+    let first = len.min(data.len() - start);
+    let mut out = Vec::with_capacity(len);
+    out.extend_from_slice(&data[start..start + first]);
+    out.extend_from_slice(&data[..len - first]);
+    out
+}
+
+/// Reads native-endian fields out of a record body.
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(bytes)
+    }
+    fn u32(&mut self) -> Option<u32> {
+        let b = self.bytes(4)?;
+        Some(u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        let b = self.bytes(8)?;
+        Some(u64::from_ne_bytes([
+            b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+        ]))
+    }
+    /// A NUL terminated string, padded to 8 bytes.
+    fn string(&mut self) -> Option<String> {
+        let rest = self.buf.get(self.pos..)?;
+        let len = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+        let s = String::from_utf8_lossy(&rest[..len]).into_owned();
+        self.pos += (len + 8) & !7;
+        Some(s)
+    }
+}
+
+impl Record {
+    /// Decode a record body. `sample_type` and `read_format`
+    /// describe the layout of `PERF_RECORD_SAMPLE` records,
+    /// and `read_format` that of `PERF_RECORD_READ` records.
+    #[allow(non_upper_case_globals)]
+    fn parse(type_: u32, misc: u16, body: &[u8], sample_type: u64, read_format: u64) -> Record {
+        let mut c = Cursor::new(body);
+        let record = match type_ {
+            perf_event_type_PERF_RECORD_SAMPLE => {
+                Sample::parse(&mut c, misc, sample_type, read_format).map(Record::Sample)
+            }
+            perf_event_type_PERF_RECORD_MMAP2 => Self::mmap2(&mut c),
+            perf_event_type_PERF_RECORD_COMM => (|| {
+                Some(Record::Comm {
+                    pid: c.u32()?,
+                    tid: c.u32()?,
+                    comm: c.string()?,
+                    exec: misc as u32 & PERF_RECORD_MISC_COMM_EXEC != 0,
+                })
+            })(),
+            perf_event_type_PERF_RECORD_FORK => Self::task(&mut c).map(Record::Fork),
+            perf_event_type_PERF_RECORD_EXIT => Self::task(&mut c).map(Record::Exit),
+            perf_event_type_PERF_RECORD_READ => (|| {
+                let (pid, tid) = (c.u32()?, c.u32()?);
+                let mut words = std::iter::from_fn(|| c.u64());
+                Some(Record::Read {
+                    pid,
+                    tid,
+                    reading: Reading::decode(read_format, &mut words),
+                })
+            })(),
+            perf_event_type_PERF_RECORD_LOST => (|| {
+                Some(Record::Lost {
+                    id: c.u64()?,
+                    lost: c.u64()?,
+                })
+            })(),
+            perf_event_type_PERF_RECORD_THROTTLE => (|| {
+                Some(Record::Throttle {
+                    time: c.u64()?,
+                    id: c.u64()?,
+                    stream_id: c.u64()?,
+                })
+            })(),
+            perf_event_type_PERF_RECORD_UNTHROTTLE => (|| {
+                Some(Record::Unthrottle {
+                    time: c.u64()?,
+                    id: c.u64()?,
+                    stream_id: c.u64()?,
+                })
+            })(),
+            _ => None,
+        };
+        // Anything unknown or truncated is handed back undecoded.
+        record.unwrap_or_else(|| Record::Other {
+            type_,
+            misc,
+            data: body.to_vec(),
+        })
+    }
+    fn task(c: &mut Cursor) -> Option<Task> {
+        Some(Task {
+            pid: c.u32()?,
+            ppid: c.u32()?,
+            tid: c.u32()?,
+            ptid: c.u32()?,
+            time: c.u64()?,
+        })
+    }
+    fn mmap2(c: &mut Cursor) -> Option<Record> {
+        Some(Record::Mmap2(Mmap2 {
+            pid: c.u32()?,
+            tid: c.u32()?,
+            addr: c.u64()?,
+            len: c.u64()?,
+            pgoff: c.u64()?,
+            maj: c.u32()?,
+            min: c.u32()?,
+            ino: c.u64()?,
+            ino_generation: c.u64()?,
+            prot: c.u32()?,
+            flags: c.u32()?,
+            filename: c.string()?,
+        }))
+    }
+}
+
+impl Sample {
+    /// Decode the fields selected by `sample_type`, in the order
+    /// the kernel writes them. Decoding stops after `PERF_SAMPLE_RAW`;
+    /// later fields such as branch stacks and registers are skipped.
+    fn parse(c: &mut Cursor, misc: u16, sample_type: u64, read_format: u64) -> Option<Sample> {
+        let has = |flag: perf_event_sample_format| sample_type & flag != 0;
+        let mut sample = Sample {
+            misc,
+            ..Default::default()
+        };
+        if has(perf_event_sample_format_PERF_SAMPLE_IDENTIFIER) {
+            sample.identifier = Some(c.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_IP) {
+            sample.ip = Some(c.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_TID) {
+            sample.pid = Some(c.u32()?);
+            sample.tid = Some(c.u32()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_TIME) {
+            sample.time = Some(c.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_ADDR) {
+            sample.addr = Some(c.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_ID) {
+            sample.id = Some(c.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_STREAM_ID) {
+            sample.stream_id = Some(c.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_CPU) {
+            sample.cpu = Some(c.u32()?);
+            c.u32()?;
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_PERIOD) {
+            sample.period = Some(c.u64()?);
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_READ) {
+            let mut words = std::iter::from_fn(|| c.u64());
+            sample.read = Some(Reading::decode(read_format, &mut words));
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_CALLCHAIN) {
+            let nr = c.u64()?;
+            for _ in 0..nr {
+                sample.callchain.push(c.u64()?);
+            }
+        }
+        if has(perf_event_sample_format_PERF_SAMPLE_RAW) {
+            let size = c.u32()? as usize;
+            sample.raw = Some(c.bytes(size)?.to_vec());
+        }
+        Some(sample)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn copy_wrapped_test() {
+    let data = [0, 1, 2, 3, 4, 5, 6, 7];
+    assert_eq!(copy_wrapped(&data, 2, 3), vec![2, 3, 4]);
+    // Wraps round the end of the buffer.
+    assert_eq!(copy_wrapped(&data, 6, 4), vec![6, 7, 0, 1]);
+    // Positions are free running, not offsets into `data`.
+    assert_eq!(copy_wrapped(&data, 8 * 3 + 7, 2), vec![7, 0]);
+}
+
+#[test]
+fn parse_records_test() {
+    let mut lost = Vec::new();
+    lost.extend_from_slice(&7_u64.to_ne_bytes());
+    lost.extend_from_slice(&42_u64.to_ne_bytes());
+    match Record::parse(perf_event_type_PERF_RECORD_LOST, 0, &lost, 0, 0) {
+        Record::Lost { id, lost } => assert_eq!((id, lost), (7, 42)),
+        r => panic!("expected Record::Lost, got {:?}", r),
+    }
+
+    let mut comm = Vec::new();
+    comm.extend_from_slice(&10_u32.to_ne_bytes());
+    comm.extend_from_slice(&11_u32.to_ne_bytes());
+    comm.extend_from_slice(b"ruperf\0\0");
+    let misc = PERF_RECORD_MISC_COMM_EXEC as u16;
+    match Record::parse(perf_event_type_PERF_RECORD_COMM, misc, &comm, 0, 0) {
+        Record::Comm {
+            pid, comm, exec, ..
+        } => {
+            assert_eq!(pid, 10);
+            assert_eq!(comm, "ruperf");
+            assert!(exec);
+        }
+        r => panic!("expected Record::Comm, got {:?}", r),
+    }
+
+    let sample_type = perf_event_sample_format_PERF_SAMPLE_IP
+        | perf_event_sample_format_PERF_SAMPLE_TID
+        | perf_event_sample_format_PERF_SAMPLE_PERIOD;
+    let mut sample = Vec::new();
+    sample.extend_from_slice(&0xdead_u64.to_ne_bytes());
+    sample.extend_from_slice(&1_u32.to_ne_bytes());
+    sample.extend_from_slice(&2_u32.to_ne_bytes());
+    sample.extend_from_slice(&1000_u64.to_ne_bytes());
+    match Record::parse(
+        perf_event_type_PERF_RECORD_SAMPLE,
+        0,
+        &sample,
+        sample_type,
+        0,
+    ) {
+        Record::Sample(s) => {
+            assert_eq!(s.ip, Some(0xdead));
+            assert_eq!((s.pid, s.tid), (Some(1), Some(2)));
+            assert_eq!(s.period, Some(1000));
+            assert_eq!(s.time, None);
+        }
+        r => panic!("expected Record::Sample, got {:?}", r),
+    }
+
+    let read_format =
+        (perf_event_read_format_PERF_FORMAT_GROUP | perf_event_read_format_PERF_FORMAT_ID) as u64;
+    let mut read = Vec::new();
+    read.extend_from_slice(&10_u32.to_ne_bytes());
+    read.extend_from_slice(&12_u32.to_ne_bytes());
+    for word in &[2_u64, 500, 1, 700, 2] {
+        read.extend_from_slice(&word.to_ne_bytes());
+    }
+    match Record::parse(perf_event_type_PERF_RECORD_READ, 0, &read, 0, read_format) {
+        Record::Read { pid, tid, reading } => {
+            assert_eq!((pid, tid), (10, 12));
+            assert_eq!(reading.values.len(), 2);
+            assert_eq!((reading.values[1].value, reading.values[1].id), (700, 2));
+        }
+        r => panic!("expected Record::Read, got {:?}", r),
+    }
+
+    // Truncated records are passed through undecoded.
+    match Record::parse(perf_event_type_PERF_RECORD_LOST, 0, &lost[..4], 0, 0) {
+        Record::Other { data, .. } => assert_eq!(data.len(), 4),
+        r => panic!("expected Record::Other, got {:?}", r),
+    }
+}
+
+#[test]
+fn cpu_clock_sample_test() {
+    let event = &mut perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_SOFTWARE,
+        size: size_of::<perf_event_attr>() as u32,
+        config: perf_sw_ids_PERF_COUNT_SW_CPU_CLOCK as u64,
+        // Sample every 100us of CPU time.
+        __bindgen_anon_1: perf_event_attr__bindgen_ty_1 {
+            sample_period: 100_000,
+        },
+        sample_type: perf_event_sample_format_PERF_SAMPLE_IP
+            | perf_event_sample_format_PERF_SAMPLE_TID
+            | perf_event_sample_format_PERF_SAMPLE_TIME
+            | perf_event_sample_format_PERF_SAMPLE_PERIOD,
+        ..Default::default()
+    };
+    event.set_disabled(1);
+    event.set_exclude_kernel(1);
+    event.set_exclude_hv(1);
+    let fd = FileDesc::new(event, None, -1, -1).unwrap();
+    let mut ring = RingBuffer::new(&fd, 8).unwrap();
+    assert!(ring.is_empty());
+    fd.enable().unwrap();
+    let start = std::time::Instant::now();
+    let mut x: u64 = 0;
+    while start.elapsed().as_millis() < 20 {
+        x = x.wrapping_mul(31).wrapping_add(7);
+    }
+    fd.disable().unwrap();
+    assert_ne!(x, 1);
+    let pid = std::process::id();
+    let samples: Vec<Sample> = ring
+        .records()
+        .filter_map(|r| match r {
+            Record::Sample(s) => Some(s),
+            _ => None,
+        })
+        .collect();
+    assert!(!samples.is_empty());
+    for s in &samples {
+        assert_eq!(s.pid, Some(pid));
+        assert!(s.ip.is_some());
+        assert_eq!(s.period, Some(100_000));
+    }
+    assert!(ring.is_empty());
+}
@@ -0,0 +1,283 @@
+//! An `EventSpec` describes an event as data:
+//! the `perf_event_attr` fields that pick the event
+//! and how it should be counted or sampled.
+//! Specs are built up with chained calls,
+//! e.g. `EventSpec::hardware(id).exclude_user(true)`,
+//! and turned into a `perf_event_attr` with `attr()`.
+
+use crate::bindings::*;
+use std::fmt;
+
+const PERF_EVENT_ATTR_SIZE: u32 = std::mem::size_of::<perf_event_attr>() as u32;
+
+/// How often a sampling event should overflow.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Sample {
+    /// Overflow every `n` events.
+    Period(u64),
+    /// Let the kernel adjust the period to
+    /// take roughly `n` samples per second.
+    Freq(u64),
+}
+
+/// A generic event, as the kernel sees it.
+/// By default only user space is counted, since that is
+/// all an unprivileged user may count with the default
+/// `perf_event_paranoid` setting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventSpec {
+    /// Name shown to the user, e.g. in `stat` output.
+    pub name: String,
+    /// One of the `PERF_TYPE_*` values, or a dynamic PMU type.
+    pub type_: u32,
+    pub config: u64,
+    pub config1: u64,
+    pub config2: u64,
+    pub exclude_user: bool,
+    pub exclude_kernel: bool,
+    pub exclude_hv: bool,
+    /// `None` for a counting event.
+    pub sample: Option<Sample>,
+    /// `PERF_SAMPLE_*` bits to record with each sample.
+    pub sample_type: u64,
+    /// Skid constraint, from 0 (arbitrary skid)
+    /// to 3 (must have no skid).
+    pub precise_ip: u8,
+    /// `HW_BREAKPOINT_*` access bits of a breakpoint,
+    /// whose address and length are `config1` and `config2`.
+    pub bp_type: u32,
+    /// Also count the threads and processes that the
+    /// counted task goes on to create.
+    pub inherit: bool,
+    /// With `inherit`, write each inherited thread's final
+    /// counts to the ring buffer as a `PERF_RECORD_READ` when it exits.
+    pub inherit_stat: bool,
+    /// Write a `PERF_RECORD_COMM` to the ring
+    /// buffer when a counted task execs or is renamed.
+    pub comm: bool,
+}
+
+impl EventSpec {
+    /// A user space only event of `type_` and `config`.
+    pub fn new(type_: u32, config: u64) -> Self {
+        EventSpec {
+            name: format!("{}:{:#x}", type_, config),
+            type_,
+            config,
+            config1: 0,
+            config2: 0,
+            exclude_user: false,
+            exclude_kernel: true,
+            exclude_hv: true,
+            sample: None,
+            sample_type: 0,
+            precise_ip: 0,
+            bp_type: HW_BREAKPOINT_EMPTY,
+            inherit: false,
+            inherit_stat: false,
+            comm: false,
+        }
+    }
+    /// A generalized hardware event, one of `perf_hw_id`.
+    pub fn hardware(id: perf_hw_id) -> Self {
+        Self::new(perf_type_id_PERF_TYPE_HARDWARE, id as u64)
+    }
+    /// A kernel software event, one of `perf_sw_ids`.
+    /// Context switches and migrations only ever
+    /// happen in the kernel, so they count kernel space.
+    #[allow(non_upper_case_globals)]
+    pub fn software(id: perf_sw_ids) -> Self {
+        let spec = Self::new(perf_type_id_PERF_TYPE_SOFTWARE, id as u64);
+        match id {
+            perf_sw_ids_PERF_COUNT_SW_CONTEXT_SWITCHES
+            | perf_sw_ids_PERF_COUNT_SW_CPU_MIGRATIONS => spec.exclude_kernel(false),
+            _ => spec,
+        }
+    }
+    /// A hardware CPU cache event. The config packs
+    /// the cache, the operation and the result as
+    /// described in the `perf_event_open()` man page.
+    pub fn cache(
+        id: perf_hw_cache_id,
+        op: perf_hw_cache_op_id,
+        result: perf_hw_cache_op_result_id,
+    ) -> Self {
+        Self::new(
+            perf_type_id_PERF_TYPE_HW_CACHE,
+            cache_config(id, op, result),
+        )
+    }
+    /// A raw, CPU specific event code.
+    pub fn raw(config: u64) -> Self {
+        Self::new(perf_type_id_PERF_TYPE_RAW, config)
+    }
+    /// A hardware breakpoint on the `len` bytes at `addr`,
+    /// counting the accesses in `bp_type`, e.g. `HW_BREAKPOINT_W`.
+    /// Named like perf's `mem:0xADDR/len:rwx` events.
+    pub fn breakpoint(addr: u64, len: u64, bp_type: u32) -> Self {
+        let mut access = String::new();
+        for (bit, c) in &[
+            (HW_BREAKPOINT_R, 'r'),
+            (HW_BREAKPOINT_W, 'w'),
+            (HW_BREAKPOINT_X, 'x'),
+        ] {
+            if bp_type & bit != 0 {
+                access.push(*c);
+            }
+        }
+        let mut spec = Self::new(perf_type_id_PERF_TYPE_BREAKPOINT, 0)
+            .config1(addr)
+            .config2(len)
+            .name(&format!("mem:{:#x}/{}:{}", addr, len, access));
+        spec.bp_type = bp_type;
+        spec
+    }
+    /// Set the name shown to the user.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+    pub fn config1(mut self, config1: u64) -> Self {
+        self.config1 = config1;
+        self
+    }
+    pub fn config2(mut self, config2: u64) -> Self {
+        self.config2 = config2;
+        self
+    }
+    pub fn exclude_user(mut self, exclude: bool) -> Self {
+        self.exclude_user = exclude;
+        self
+    }
+    pub fn exclude_kernel(mut self, exclude: bool) -> Self {
+        self.exclude_kernel = exclude;
+        self
+    }
+    pub fn exclude_hv(mut self, exclude: bool) -> Self {
+        self.exclude_hv = exclude;
+        self
+    }
+    pub fn inherit(mut self, inherit: bool) -> Self {
+        self.inherit = inherit;
+        self
+    }
+    pub fn precise_ip(mut self, precise_ip: u8) -> Self {
+        self.precise_ip = precise_ip;
+        self
+    }
+    /// Make this a sampling event.
+    pub fn sample(mut self, sample: Sample, sample_type: u64) -> Self {
+        self.sample = Some(sample);
+        self.sample_type = sample_type;
+        self
+    }
+    /// Is this the task clock, which is reported in msec?
+    pub fn is_task_clock(&self) -> bool {
+        self.type_ == perf_type_id_PERF_TYPE_SOFTWARE
+            && self.config == perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK as u64
+    }
+    /// Build the attributes to open this event with.
+    /// The event starts disabled, to be enabled
+    /// once whatever is being measured is ready.
+    pub fn attr(&self) -> perf_event_attr {
+        let attr = &mut perf_event_attr {
+            type_: self.type_,
+            size: PERF_EVENT_ATTR_SIZE,
+            config: self.config,
+            sample_type: self.sample_type,
+            bp_type: self.bp_type,
+            ..Default::default()
+        };
+        attr.__bindgen_anon_3.config1 = self.config1;
+        attr.__bindgen_anon_4.config2 = self.config2;
+        match self.sample {
+            Some(Sample::Period(period)) => attr.__bindgen_anon_1.sample_period = period,
+            Some(Sample::Freq(freq)) => {
+                attr.__bindgen_anon_1.sample_freq = freq;
+                attr.set_freq(1);
+            }
+            None => {}
+        }
+        attr.set_disabled(1);
+        attr.set_exclude_user(self.exclude_user as u64);
+        attr.set_exclude_kernel(self.exclude_kernel as u64);
+        attr.set_exclude_hv(self.exclude_hv as u64);
+        attr.set_precise_ip(self.precise_ip as u64);
+        attr.set_inherit(self.inherit as u64);
+        attr.set_inherit_stat(self.inherit_stat as u64);
+        attr.set_comm(self.comm as u64);
+        *attr
+    }
+}
+
+/// The `config` of a `PERF_TYPE_HW_CACHE` event.
+pub const fn cache_config(
+    id: perf_hw_cache_id,
+    op: perf_hw_cache_op_id,
+    result: perf_hw_cache_op_result_id,
+) -> u64 {
+    (id as u64) | ((op as u64) << 8) | ((result as u64) << 16)
+}
+
+impl fmt::Display for EventSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn attr_test() {
+    let attr = EventSpec::hardware(perf_hw_id_PERF_COUNT_HW_INSTRUCTIONS)
+        .config1(1)
+        .exclude_user(true)
+        .exclude_kernel(false)
+        .attr();
+    assert_eq!(attr.type_, perf_type_id_PERF_TYPE_HARDWARE);
+    assert_eq!(attr.size, PERF_EVENT_ATTR_SIZE);
+    assert_eq!(attr.config, perf_hw_id_PERF_COUNT_HW_INSTRUCTIONS as u64);
+    assert_eq!(unsafe { attr.__bindgen_anon_3.config1 }, 1);
+    assert_eq!(attr.disabled(), 1);
+    assert_eq!(attr.exclude_user(), 1);
+    assert_eq!(attr.exclude_kernel(), 0);
+    assert_eq!(attr.exclude_hv(), 1);
+    assert_eq!(attr.freq(), 0);
+    assert_eq!(attr.inherit(), 0);
+    let attr = EventSpec::software(perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK)
+        .inherit(true)
+        .attr();
+    assert_eq!(attr.inherit(), 1);
+}
+
+#[test]
+fn cache_config_test() {
+    let spec = EventSpec::cache(
+        perf_hw_cache_id_PERF_COUNT_HW_CACHE_LL,
+        perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_WRITE,
+        perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_MISS,
+    );
+    assert_eq!(spec.type_, perf_type_id_PERF_TYPE_HW_CACHE);
+    assert_eq!(spec.config, 0x10102);
+}
+
+#[test]
+fn sample_attr_test() {
+    let attr = EventSpec::software(perf_sw_ids_PERF_COUNT_SW_CPU_CLOCK)
+        .sample(Sample::Freq(99), perf_event_sample_format_PERF_SAMPLE_IP)
+        .attr();
+    assert_eq!(attr.freq(), 1);
+    assert_eq!(unsafe { attr.__bindgen_anon_1.sample_freq }, 99);
+    assert_eq!(attr.sample_type, perf_event_sample_format_PERF_SAMPLE_IP);
+}
+
+#[test]
+fn breakpoint_attr_test() {
+    let spec = EventSpec::breakpoint(0x1000, 8, HW_BREAKPOINT_RW);
+    assert_eq!(spec.name, "mem:0x1000/8:rw");
+    let attr = spec.attr();
+    assert_eq!(attr.type_, perf_type_id_PERF_TYPE_BREAKPOINT);
+    assert_eq!(attr.config, 0);
+    assert_eq!(attr.bp_type, HW_BREAKPOINT_RW);
+    assert_eq!(unsafe { attr.__bindgen_anon_3.bp_addr }, 0x1000);
+    assert_eq!(unsafe { attr.__bindgen_anon_4.bp_len }, 8);
+}
@@ -1,15 +1,48 @@
-//! A safe wrapper for the `read()`
-//! Linux system call. For more on
-//! `read()` see the Linux man-page.
+//! Safe wrappers for the `read()`, `mmap()`
+//! and `munmap()` Linux system calls. For more on
+//! them see the Linux man-pages.
 
 extern crate libc;
-use libc::read;
+use libc::{mmap, munmap, read, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
 
-pub fn read_wrap(fd: i32) -> isize {
-    let mut count: isize = 0;
+/// Read up to `buf.len()` `u64` words from `fd`.
+/// The layout of what is read depends on the
+/// `read_format` the event was opened with.
+/// Returns the number of bytes read, or -1 on failure.
+pub fn read_wrap(fd: i32, buf: &mut [u64]) -> isize {
     unsafe {
-        let buf: *mut libc::c_void = &mut count as *mut _ as *mut libc::c_void;
-        read(fd, buf, std::mem::size_of_val(&count));
+        read(
+            fd,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            std::mem::size_of_val(buf),
+        )
+    }
+}
+
+/// Map `len` bytes of `fd` shared and read/write,
+/// as the perf ring buffer requires.
+/// Returns `None` if `mmap()` fails.
+pub fn mmap_wrap(fd: i32, len: usize) -> Option<*mut u8> {
+    let ret = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            len,
+            PROT_READ | PROT_WRITE,
+            MAP_SHARED,
+            fd,
+            0,
+        )
+    };
+    if ret == MAP_FAILED {
+        return None;
+    }
+    Some(ret as *mut u8)
+}
+
+/// Unmap a region returned by `mmap_wrap()`.
+/// `addr` must not be used afterwards.
+pub fn munmap_wrap(addr: *mut u8, len: usize) {
+    unsafe {
+        munmap(addr as *mut libc::c_void, len);
     }
-    count
 }
@@ -0,0 +1,293 @@
+//! Kernel tracepoints, as tracefs describes them.
+//! Every tracepoint has a directory `events/<system>/<name>` with:
+//! - `id`, the `perf_event_attr.config` to open it with,
+//! - `format`, the layout of the raw data it records,
+//!   one field per line, e.g.
+//!   `field:pid_t prev_pid; offset:24; size:4; signed:1;`
+//!
+//! This is what lets `system:name` events be turned into
+//! an `EventSpec`, and their `PERF_SAMPLE_RAW` data decoded.
+
+use crate::bindings::*;
+use crate::event::spec::EventSpec;
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where tracefs may be mounted, newest first.
+pub const TRACEFS: &[&str] = &["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// The `events` directory of the first mounted
+/// tracefs, or `None` if there isn't one.
+pub fn tracefs_events() -> Option<PathBuf> {
+    TRACEFS
+        .iter()
+        .map(|root| Path::new(root).join("events"))
+        .find(|p| p.is_dir())
+}
+
+/// One field of a tracepoint's raw data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    /// The C type, without the array length,
+    /// e.g. `char` or `__data_loc char[]`.
+    pub type_: String,
+    /// Number of elements, if the field is a fixed size array.
+    pub array: Option<usize>,
+    pub offset: usize,
+    pub size: usize,
+    pub signed: bool,
+}
+
+impl Field {
+    /// Parse a `field:...;` line of a format file.
+    fn parse(line: &str) -> Option<Self> {
+        let mut decl = None;
+        let (mut offset, mut size, mut signed) = (None, None, false);
+        for item in line.split(';').map(str::trim).filter(|i| !i.is_empty()) {
+            let (key, value) = item.split_once(':')?;
+            match key {
+                "field" => decl = Some(value.trim()),
+                "offset" => offset = value.parse().ok(),
+                "size" => size = value.parse().ok(),
+                "signed" => signed = value == "1",
+                _ => {}
+            }
+        }
+        // The name is the last word of the declaration,
+        // e.g. `filename` in `const char * filename`.
+        let decl = decl?;
+        let split = decl.rfind([' ', '*'])? + 1;
+        let (type_, mut name) = (decl[..split].trim(), &decl[split..]);
+        let mut array = None;
+        if let Some((base, len)) = name.split_once('[') {
+            name = base;
+            array = Some(len.strip_suffix(']')?.parse().ok()?);
+        }
+        Some(Field {
+            name: name.to_string(),
+            type_: type_.to_string(),
+            array,
+            offset: offset?,
+            size: size?,
+            signed,
+        })
+    }
+    /// Is this one of the fields every tracepoint starts with?
+    pub fn is_common(&self) -> bool {
+        self.name.starts_with("common_")
+    }
+    /// This field's value in `raw`, or `None`
+    /// if `raw` is too short to hold it.
+    fn decode(&self, raw: &[u8]) -> Option<Value> {
+        let bytes = raw.get(self.offset..self.offset + self.size)?;
+        // A `__data_loc` field holds where its data really
+        // is: the offset in the low 16 bits, the length in the high.
+        if self.type_.starts_with("__data_loc") {
+            let loc = u32::from_ne_bytes(bytes.try_into().ok()?) as usize;
+            let data = raw.get(loc & 0xffff..(loc & 0xffff) + (loc >> 16))?;
+            if self.type_.contains("char") {
+                return Some(Value::string(data));
+            }
+            return Some(Value::Bytes(data.to_vec()));
+        }
+        if self.array.is_some() {
+            if self.type_ == "char" {
+                return Some(Value::string(bytes));
+            }
+            return Some(Value::Bytes(bytes.to_vec()));
+        }
+        let value = match self.size {
+            1 => bytes[0] as u64,
+            2 => u16::from_ne_bytes(bytes.try_into().ok()?) as u64,
+            4 => u32::from_ne_bytes(bytes.try_into().ok()?) as u64,
+            8 => u64::from_ne_bytes(bytes.try_into().ok()?),
+            _ => return Some(Value::Bytes(bytes.to_vec())),
+        };
+        if !self.signed {
+            return Some(Value::Uint(value));
+        }
+        // Sign extend from the field's width.
+        let shift = 64 - 8 * self.size as u32;
+        Some(Value::Int(((value << shift) as i64) >> shift))
+    }
+}
+
+/// A decoded field value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Uint(u64),
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    /// A C string, up to its first NUL.
+    fn string(bytes: &[u8]) -> Self {
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        Value::Str(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+}
+
+/// A tracepoint, read from tracefs.
+#[derive(Debug, Clone)]
+pub struct Tracepoint {
+    pub system: String,
+    pub name: String,
+    pub id: u64,
+    pub fields: Vec<Field>,
+}
+
+impl Tracepoint {
+    /// Read the tracepoint `system:name` from the `events`
+    /// directory `root`, or `None` if there's no such tracepoint.
+    pub fn open(root: &Path, system: &str, name: &str) -> Option<Self> {
+        let dir = root.join(system).join(name);
+        let id = fs::read_to_string(dir.join("id")).ok()?;
+        // Reading the format may need more privilege than the id,
+        // without it the tracepoint can still be counted.
+        let fields = match fs::read_to_string(dir.join("format")) {
+            Ok(format) => parse_format(&format),
+            Err(_) => Vec::new(),
+        };
+        Some(Tracepoint {
+            system: system.to_string(),
+            name: name.to_string(),
+            id: id.trim().parse().ok()?,
+            fields,
+        })
+    }
+    /// A spec to count this tracepoint. Tracepoints
+    /// only fire in the kernel, so kernel space is counted.
+    pub fn spec(&self) -> EventSpec {
+        EventSpec::new(perf_type_id_PERF_TYPE_TRACEPOINT, self.id)
+            .exclude_kernel(false)
+            .name(&format!("{}:{}", self.system, self.name))
+    }
+    /// Decode the `PERF_SAMPLE_RAW` data of a sample of this
+    /// tracepoint into its fields, in the order they're laid out.
+    /// Returns `None` if `raw` is too short for the format.
+    pub fn decode(&self, raw: &[u8]) -> Option<Vec<(&str, Value)>> {
+        self.fields
+            .iter()
+            .map(|f| Some((f.name.as_str(), f.decode(raw)?)))
+            .collect()
+    }
+}
+
+/// Parse the fields out of a tracepoint's `format` file.
+/// Lines other than fields, like `name:` and `print fmt:`, are skipped.
+pub fn parse_format(format: &str) -> Vec<Field> {
+    format
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("field:"))
+        .filter_map(Field::parse)
+        .collect()
+}
+
+/// Every tracepoint under the `events` directory
+/// `root`, as `system:name`, sorted.
+pub fn tracepoints(root: &Path) -> Vec<String> {
+    let mut names = Vec::new();
+    for system in dir_names(root) {
+        for name in dir_names(&root.join(&system)) {
+            if root.join(&system).join(&name).join("id").is_file() {
+                names.push(format!("{}:{}", system, name));
+            }
+        }
+    }
+    names
+}
+
+/// The sorted names of the entries in `dir`,
+/// or nothing if it can't be read.
+fn dir_names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+#[test]
+fn parse_format_test() {
+    use crate::event::utils::fixture;
+    let tracefs = fixture("tracefs/events");
+    let switch = Tracepoint::open(&tracefs, "sched", "sched_switch").unwrap();
+    assert_eq!(switch.id, 316);
+    assert_eq!(switch.fields.len(), 11);
+    assert_eq!(switch.fields.iter().filter(|f| f.is_common()).count(), 4);
+    let comm = &switch.fields[4];
+    assert_eq!(comm.name, "prev_comm");
+    assert_eq!(comm.type_, "char");
+    assert_eq!(comm.array, Some(16));
+    assert_eq!((comm.offset, comm.size, comm.signed), (8, 16, false));
+    let openat = Tracepoint::open(&tracefs, "syscalls", "sys_enter_openat").unwrap();
+    let filename = &openat.fields[6];
+    assert_eq!(filename.name, "filename");
+    assert_eq!(filename.type_, "const char *");
+    let issue = Tracepoint::open(&tracefs, "block", "block_rq_issue").unwrap();
+    let cmd = issue.fields.last().unwrap();
+    assert_eq!(cmd.name, "cmd");
+    assert_eq!(cmd.type_, "__data_loc char[]");
+    assert_eq!(cmd.array, None);
+    assert!(Tracepoint::open(&tracefs, "sched", "bogus").is_none());
+}
+
+#[test]
+fn decode_test() {
+    use crate::event::utils::fixture;
+    let tracefs = fixture("tracefs/events");
+    let switch = Tracepoint::open(&tracefs, "sched", "sched_switch").unwrap();
+    let mut raw = vec![0u8; 64];
+    raw[0..2].copy_from_slice(&316u16.to_ne_bytes());
+    raw[8..12].copy_from_slice(b"bash");
+    raw[24..28].copy_from_slice(&42i32.to_ne_bytes());
+    raw[28..32].copy_from_slice(&(-20i32).to_ne_bytes());
+    let fields = switch.decode(&raw).unwrap();
+    assert_eq!(fields[0], ("common_type", Value::Uint(316)));
+    assert_eq!(fields[4], ("prev_comm", Value::Str("bash".to_string())));
+    assert_eq!(fields[5], ("prev_pid", Value::Int(42)));
+    assert_eq!(fields[6], ("prev_prio", Value::Int(-20)));
+    assert!(switch.decode(&raw[..40]).is_none());
+
+    let issue = Tracepoint::open(&tracefs, "block", "block_rq_issue").unwrap();
+    let mut raw = vec![0u8; 64];
+    raw[56..60].copy_from_slice(&((3u32 << 16) | 60).to_ne_bytes());
+    raw[60..63].copy_from_slice(b"ab\0");
+    let fields = issue.decode(&raw).unwrap();
+    assert_eq!(
+        fields.last().unwrap(),
+        &("cmd", Value::Str("ab".to_string()))
+    );
+}
+
+#[test]
+fn tracepoints_test() {
+    use crate::event::utils::fixture;
+    let tracefs = fixture("tracefs/events");
+    let names = tracepoints(&tracefs);
+    assert_eq!(
+        names,
+        vec![
+            "block:block_rq_issue",
+            "sched:sched_switch",
+            "syscalls:sys_enter_openat"
+        ]
+    );
+    let spec = Tracepoint::open(&tracefs, "sched", "sched_switch")
+        .unwrap()
+        .spec();
+    assert_eq!(spec.type_, perf_type_id_PERF_TYPE_TRACEPOINT);
+    assert_eq!(spec.config, 316);
+    assert_eq!(spec.name, "sched:sched_switch");
+    assert!(!spec.exclude_kernel);
+}
@@ -1,21 +1,175 @@
 //! This file may contain more items in the
 //! future. For now it defines a generic `Result`
-//! type for handling system call failures and
-//! invalid event requests.
+//! type and the errors for handling system call
+//! failures and invalid event requests.
+
+use crate::bindings::*;
+use std::fmt;
+use std::io;
+use thiserror::Error;
 
 type Result<T, E> = std::result::Result<T, E>;
 
-/// Errors related to system calls.
-#[derive(Debug)]
+/// Errors related to system calls. Where the kernel
+/// reported one, the variant carries its `errno`.
+#[derive(Error, Debug)]
 pub enum SysErr {
-    ReadFail,
-    IoFail,
+    #[error("perf_event_open() failed: {}", io::Error::from_raw_os_error(*.errno))]
+    Open { errno: i32, attr: EventAttr },
+    #[error("read() failed: {}", io::Error::from_raw_os_error(*.0))]
+    ReadFail(i32),
+    #[error("ioctl() failed: {}", io::Error::from_raw_os_error(*.0))]
+    IoFail(i32),
+    #[error("mmap() failed: {}", io::Error::from_raw_os_error(*.0))]
+    MmapFail(i32),
+    #[error("invalid ioctl() argument")]
     IoArg,
+    #[error("ioctl() returned an invalid event id")]
     IoId,
 }
 
+impl SysErr {
+    /// Build a `SysErr::Open` from the current `errno`.
+    pub fn open(attr: &perf_event_attr) -> Self {
+        SysErr::Open {
+            errno: errno(),
+            attr: EventAttr(*attr),
+        }
+    }
+    /// The `errno` reported by the kernel, if any.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            SysErr::Open { errno, .. } => Some(*errno),
+            SysErr::ReadFail(errno) | SysErr::IoFail(errno) | SysErr::MmapFail(errno) => {
+                Some(*errno)
+            }
+            SysErr::IoArg | SysErr::IoId => None,
+        }
+    }
+    /// Did `perf_event_open()` fail because this
+    /// kernel or CPU can't count the event at all? Like
+    /// perf, an invalid cache event is one it can't count:
+    /// x86 refuses cache ops its CPUs lack with `EINVAL`.
+    pub fn is_unsupported(&self) -> bool {
+        match self {
+            SysErr::Open { errno, attr } => match *errno {
+                libc::ENOENT | libc::EOPNOTSUPP | libc::ENXIO => true,
+                libc::EINVAL => attr.0.type_ == perf_type_id_PERF_TYPE_HW_CACHE,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+    /// A suggestion for the user on how to get past
+    /// a failed `perf_event_open()`, in the spirit of perf's.
+    pub fn hint(&self) -> Option<&'static str> {
+        if !matches!(self, SysErr::Open { .. }) {
+            return None;
+        }
+        if self.is_unsupported() {
+            return Some("The event is not supported by this kernel or CPU.");
+        }
+        match self.errno()? {
+            libc::EACCES | libc::EPERM => Some(
+                "You may not have permission to collect stats.\n\
+                 Consider lowering /proc/sys/kernel/perf_event_paranoid, \
+                 or granting ruperf CAP_PERFMON (CAP_SYS_ADMIN before Linux 5.8).",
+            ),
+            libc::ENODEV => Some("The PMU for this event is not available on this machine."),
+            libc::EMFILE => Some(
+                "Too many open files. Raise the limit with `ulimit -n`, \
+                 or count fewer events at once.",
+            ),
+            libc::EINVAL => Some(
+                "The kernel rejected the event attributes. \
+                 The event or one of its modifiers may not be supported.",
+            ),
+            libc::ESRCH => Some("The process to count does not exist."),
+            _ => None,
+        }
+    }
+}
+
+/// `path` under the fixtures checked in at `tests/fixtures`.
+#[cfg(test)]
+pub fn fixture(path: &str) -> std::path::PathBuf {
+    std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(path)
+}
+
+/// The `errno` left behind by the last failed system call.
+pub fn errno() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+/// The `perf_event_attr` a failed `perf_event_open()` was
+/// called with. Wrapped so errors can be debug printed,
+/// since the generated bindings don't implement `Debug`.
+#[derive(Copy, Clone)]
+pub struct EventAttr(pub perf_event_attr);
+
+impl fmt::Debug for EventAttr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let attr = &self.0;
+        f.debug_struct("perf_event_attr")
+            .field("type", &attr.type_)
+            .field("size", &attr.size)
+            .field("config", &format_args!("{:#x}", attr.config))
+            .field(
+                "config1",
+                &format_args!("{:#x}", unsafe { attr.__bindgen_anon_3.config1 }),
+            )
+            .field(
+                "config2",
+                &format_args!("{:#x}", unsafe { attr.__bindgen_anon_4.config2 }),
+            )
+            .field("read_format", &format_args!("{:#x}", attr.read_format))
+            .field("sample_type", &format_args!("{:#x}", attr.sample_type))
+            .field("disabled", &attr.disabled())
+            .field("inherit", &attr.inherit())
+            .field("exclude_user", &attr.exclude_user())
+            .field("exclude_kernel", &attr.exclude_kernel())
+            .field("exclude_hv", &attr.exclude_hv())
+            .finish()
+    }
+}
+
+/// An event string that could not be parsed.
+/// `column` counts characters from 1.
+#[derive(Error, Debug, PartialEq)]
+#[error("{msg} at column {column}")]
+pub struct SyntaxErr {
+    pub column: usize,
+    pub msg: String,
+}
+
 /// Errors related to handling specific events.
-#[derive(Debug)]
+#[derive(Error, Debug)]
 pub enum EventErr {
+    #[error("Invalid Event")]
     InvalidEvent,
+    #[error("failed to open event '{event}': {source}")]
+    Open {
+        event: String,
+        #[source]
+        source: SysErr,
+    },
+    #[error(transparent)]
+    Sys(#[from] SysErr),
+}
+
+impl EventErr {
+    /// Is the event one the kernel or CPU can't count?
+    pub fn is_unsupported(&self) -> bool {
+        matches!(self.sys(), Some(e) if e.is_unsupported())
+    }
+    /// The underlying system call error, if any.
+    pub fn sys(&self) -> Option<&SysErr> {
+        match self {
+            EventErr::Open { source, .. } => Some(source),
+            EventErr::Sys(e) => Some(e),
+            EventErr::InvalidEvent => None,
+        }
+    }
 }
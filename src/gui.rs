@@ -134,12 +134,8 @@ impl Application for Gui {
                     }
 
                     // Stat Options
-                    Message::CyclesToggled(value) => {
-                        data_state.launch_options.cycles = value;
-                    }
-
-                    Message::InstructionsToggled(value) => {
-                        data_state.launch_options.instructions = value;
+                    Message::EventToggled(i, value) => {
+                        data_state.launch_options.events[i].1 = value;
                     }
 
                     // Test Options
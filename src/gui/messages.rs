@@ -11,8 +11,7 @@ pub mod main {
         InputChanged(String),
         NewAppPressed,
         CommandSelected(PerfEvent),
-        CyclesToggled(bool),
-        InstructionsToggled(bool),
+        EventToggled(usize, bool),
         JsonToggled(bool),
         ListToggled(bool),
         VerboseToggled(bool),
@@ -68,6 +68,7 @@ pub mod main {
 
 pub mod pane {
 
+    use crate::event::open::generic_events;
     use crate::gui::events::*;
     use crate::gui::widgets::task::Task;
 
@@ -122,11 +123,11 @@ pub mod pane {
 
             match self.selected_command {
                 perf::PerfEvent::Stat => {
-                    if self.launch_options.cycles {
-                        res.push_str(" --event cycles");
-                    }
-                    if self.launch_options.instructions {
-                        res.push_str(" --event instructions");
+                    for (event, checked) in &self.launch_options.events {
+                        if *checked {
+                            res.push_str(" --event ");
+                            res.push_str(event);
+                        }
                     }
                 }
 
@@ -166,8 +167,9 @@ pub mod pane {
 
     #[derive(Debug)]
     pub struct Options {
-        pub cycles: bool,
-        pub instructions: bool,
+        /// Every generic event stat can count,
+        /// and whether it is checked.
+        pub events: Vec<(String, bool)>,
         pub json: bool,
         pub list: bool,
         pub verbose: bool,
@@ -176,8 +178,10 @@ pub mod pane {
     impl Default for Options {
         fn default() -> Self {
             Options {
-                cycles: false,
-                instructions: false,
+                events: generic_events()
+                    .into_iter()
+                    .map(|spec| (spec.name, false))
+                    .collect(),
                 json: false,
                 list: false,
                 verbose: false,
@@ -137,25 +137,30 @@ pub mod panes {
                                         {
                                             //these are the options for each individual event selected:
                                             match content.selected_command {
-                                                PerfEvent::Stat => {
-                                                    Container::new(Column::with_children(vec![
-                                                        Checkbox::new(
-                                                            content.launch_options.cycles,
-                                                            "Cycles",
-                                                            Message::CyclesToggled,
-                                                        )
-                                                        .into(),
-                                                        Space::new(Length::Fill, Length::from(10))
-                                                            .into(),
-                                                        Checkbox::new(
-                                                            content.launch_options.instructions,
-                                                            "Instructions",
-                                                            Message::InstructionsToggled,
-                                                        )
-                                                        .into(),
-                                                    ]))
-                                                    .into()
-                                                }
+                                                PerfEvent::Stat => Container::new(
+                                                    Column::with_children(
+                                                        content
+                                                            .launch_options
+                                                            .events
+                                                            .iter()
+                                                            .enumerate()
+                                                            .map(|(i, (event, checked))| {
+                                                                Checkbox::new(
+                                                                    *checked,
+                                                                    event,
+                                                                    move |value| {
+                                                                        Message::EventToggled(
+                                                                            i, value,
+                                                                        )
+                                                                    },
+                                                                )
+                                                                .into()
+                                                            })
+                                                            .collect(),
+                                                    )
+                                                    .spacing(10),
+                                                )
+                                                .into(),
                                                 PerfEvent::Test => {
                                                     Container::new(Column::with_children(vec![
                                                         Checkbox::new(
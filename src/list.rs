@@ -0,0 +1,302 @@
+//! # List driver.
+//! <p> Usage: <em> ruperf list [--json] [FILTER] </em>
+//! Where FILTER is a glob, like <em>'*-misses'</em>, or one of
+//! <em>hw</em>, <em>sw</em>, <em>cache</em>, <em>breakpoint</em>,
+//! <em>tracepoint</em>, <em>pmu</em>, <em>vendor</em> or <em>metric</em>. </p>
+
+extern crate structopt;
+use crate::event::open::*;
+use serde_json::json;
+use std::path::Path;
+use structopt::StructOpt;
+
+/// Configuration settings for running list.
+#[derive(Debug, StructOpt)]
+pub struct ListOptions {
+    #[structopt(short, long, help = "Format output as json")]
+    pub json: bool,
+
+    #[structopt(help = "Glob to filter events by, or an event kind: \
+                hw, sw, cache, breakpoint, tracepoint, pmu, vendor, metric")]
+    pub filter: Option<String>,
+}
+
+/// The kinds of event, as perf names them in `perf list`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Kind {
+    Hardware,
+    Software,
+    Cache,
+    Raw,
+    Breakpoint,
+    Tracepoint,
+    Pmu,
+    /// From this CPU's pmu-events tables.
+    Vendor,
+    Metric,
+}
+
+impl Kind {
+    fn describe(&self) -> &'static str {
+        match self {
+            Kind::Hardware => "Hardware event",
+            Kind::Software => "Software event",
+            Kind::Cache => "Hardware cache event",
+            Kind::Raw => "Raw hardware event descriptor",
+            Kind::Breakpoint => "Hardware breakpoint",
+            Kind::Tracepoint => "Tracepoint event",
+            Kind::Pmu => "Kernel PMU event",
+            Kind::Vendor => "Vendor event",
+            Kind::Metric => "Metric",
+        }
+    }
+    /// The short names `perf list` accepts in place of a glob.
+    fn matches(&self, filter: &str) -> bool {
+        match self {
+            Kind::Hardware => filter == "hw" || filter == "hardware",
+            Kind::Software => filter == "sw" || filter == "software",
+            Kind::Cache => filter == "cache" || filter == "hwcache",
+            Kind::Raw => false,
+            Kind::Breakpoint => filter == "breakpoint",
+            Kind::Tracepoint => filter == "tracepoint",
+            Kind::Pmu => filter == "pmu",
+            Kind::Vendor => filter == "vendor",
+            Kind::Metric => filter == "metric" || filter == "metricgroup",
+        }
+    }
+}
+
+/// One line of the listing. `supported` is `None` for
+/// events that were not, or could not be, probed.
+struct Entry {
+    name: String,
+    aliases: Vec<String>,
+    kind: Kind,
+    /// What to open to see if it's supported, until it's probed.
+    spec: Option<EventSpec>,
+    supported: Option<bool>,
+}
+
+impl Entry {
+    fn new(name: &str, kind: Kind) -> Self {
+        Entry {
+            name: name.to_string(),
+            aliases: Vec::new(),
+            kind,
+            spec: None,
+            supported: None,
+        }
+    }
+    /// An event counted with `spec`, which `probe` opens.
+    fn countable(name: &str, kind: Kind, spec: EventSpec) -> Self {
+        Entry {
+            spec: Some(spec),
+            ..Entry::new(name, kind)
+        }
+    }
+    /// Open the entry's spec on ourselves to see if this machine
+    /// can count it. Failing for any reason other than the
+    /// event being unsupported, e.g. permissions, is inconclusive.
+    fn probe(&mut self) {
+        if let Some(spec) = self.spec.take() {
+            self.supported = match Event::new(spec, None) {
+                Ok(_) => Some(true),
+                Err(e) if e.is_unsupported() => Some(false),
+                Err(_) => None,
+            };
+        }
+    }
+    fn matches(&self, filter: &str) -> bool {
+        self.kind.matches(filter)
+            || glob(filter, &self.name)
+            || self.aliases.iter().any(|a| glob(filter, a))
+    }
+}
+
+/// Does `text` match `pattern`, where `*` matches
+/// any run of characters and `?` any one character?
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Backtrack to just after the last `*` on a mismatch,
+    // letting it swallow one more character each time.
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((sp, st)) => {
+                    p = sp + 1;
+                    t = st + 1;
+                    star = Some((sp, st + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Every tracepoint, as `subsystem:name`.
+fn tracepoint_entries() -> Vec<Entry> {
+    match tracefs_events() {
+        Some(root) => tracepoints(&root)
+            .iter()
+            .map(|name| Entry::new(name, Kind::Tracepoint))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Every named event a dynamic PMU exports, as `pmu/event/`.
+fn pmu_events() -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for pmu in Pmu::all(Path::new(EVENT_SOURCE)) {
+        for event in pmu.events.keys() {
+            let name = format!("{}/{}/", pmu.name, event);
+            entries.push(match pmu.event(event) {
+                Some(spec) => Entry::countable(&name, Kind::Pmu, spec),
+                None => Entry::new(&name, Kind::Pmu),
+            });
+        }
+    }
+    entries
+}
+
+/// The events and metrics in this CPU's pmu-events tables. There
+/// are hundreds of events, so they aren't probed. Metrics are
+/// listed with the groups they're in as aliases, for `-M`.
+fn vendor_entries() -> Vec<Entry> {
+    let tables = match PmuEvents::for_this_cpu() {
+        Some(tables) => tables,
+        None => return Vec::new(),
+    };
+    let mut entries: Vec<Entry> = tables
+        .events
+        .iter()
+        .map(|e| Entry::new(&e.name.to_lowercase(), Kind::Vendor))
+        .collect();
+    for metric in &tables.metrics {
+        entries.push(Entry {
+            aliases: metric.groups.clone(),
+            ..Entry::new(&metric.name, Kind::Metric)
+        });
+    }
+    entries
+}
+
+/// Gather every event we know how to count.
+fn entries() -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for spec in generic_events() {
+        let kind = if spec.type_ == crate::bindings::perf_type_id_PERF_TYPE_HARDWARE {
+            Kind::Hardware
+        } else {
+            Kind::Software
+        };
+        let mut entry = Entry::countable(&spec.name.clone(), kind, spec);
+        entry.aliases = aliases(&entry.name).iter().map(|a| a.to_string()).collect();
+        entries.push(entry);
+    }
+    for spec in cache_events() {
+        entries.push(Entry::countable(&spec.name.clone(), Kind::Cache, spec));
+    }
+    entries.push(Entry::new("rNNN", Kind::Raw));
+    entries.push(Entry::new("cpu/t1=v1[,t2=v2,t3 ...]/modifier", Kind::Raw));
+    entries.push(Entry::new("mem:<addr>[/len][:access]", Kind::Breakpoint));
+    entries.extend(tracepoint_entries());
+    entries.extend(pmu_events());
+    entries.extend(vendor_entries());
+    entries
+}
+
+/// List the events ruperf can count, marking
+/// those this machine doesn't support.
+pub fn run_list(options: ListOptions) {
+    let mut entries: Vec<Entry> = entries()
+        .into_iter()
+        .filter(|e| match &options.filter {
+            Some(filter) => e.matches(filter),
+            None => true,
+        })
+        .collect();
+    // Only probe what's listed; opening every event is slow.
+    for entry in &mut entries {
+        entry.probe();
+    }
+
+    if options.json {
+        let events: Vec<serde_json::Value> = entries
+            .iter()
+            .map(|e| {
+                json!({
+                    "name": e.name,
+                    "aliases": e.aliases,
+                    "type": e.kind.describe(),
+                    "supported": e.supported,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&events).unwrap());
+        return;
+    }
+
+    for entry in entries {
+        let mut name = entry.name.clone();
+        for alias in &entry.aliases {
+            name.push_str(" OR ");
+            name.push_str(alias);
+        }
+        let supported = match entry.supported {
+            Some(false) => " <not supported>",
+            _ => "",
+        };
+        println!("  {:<50} [{}]{}", name, entry.kind.describe(), supported);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn glob_test() {
+    assert!(glob("*", ""));
+    assert!(glob("*-misses", "LLC-load-misses"));
+    assert!(glob("L1-?cache-*", "L1-dcache-loads"));
+    assert!(glob("*cache*", "cache-references"));
+    assert!(glob("a*b*c", "aXbYbZc"));
+    assert!(!glob("*-misses", "LLC-loads"));
+    assert!(!glob("cycles", "cpu-cycles"));
+    assert!(!glob("a?", "a"));
+}
+
+#[test]
+fn entry_matches_test() {
+    let entry = Entry {
+        aliases: vec!["cycles".to_string()],
+        ..Entry::new("cpu-cycles", Kind::Hardware)
+    };
+    assert!(entry.matches("cycles"));
+    assert!(entry.matches("hw"));
+    assert!(!entry.matches("sw"));
+    assert!(!entry.matches("instructions"));
+}
+
+#[test]
+fn probe_test() {
+    let spec = cache_events()
+        .into_iter()
+        .find(|spec| spec.name == "L1-icache-stores")
+        .unwrap();
+    let mut entry = Entry::countable(&spec.name.clone(), Kind::Cache, spec);
+    assert!(entry.supported.is_none());
+    entry.probe();
+    assert_eq!(entry.supported, Some(false));
+    assert!(entry.spec.is_none());
+}
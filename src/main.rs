@@ -5,17 +5,20 @@
 //! <li>test</li>
 //! <li>stat</li>
 //! <li>gui</li>
+//! <li>list</li>
 //! </ul>
 
 mod bindings;
 mod event;
 mod gui;
+mod list;
 mod stat;
 mod test;
 mod utils;
 
 extern crate structopt;
 use gui::*;
+use list::*;
 use stat::*;
 use structopt::StructOpt;
 use test::*;
@@ -25,7 +28,6 @@ use test::*;
 enum Opt {
     #[structopt(
         setting = structopt::clap::AppSettings::TrailingVarArg,
-        setting = structopt::clap::AppSettings::AllowLeadingHyphen,
         name = "stat",
         about = "Collects hardware/software event counters",
     )]
@@ -44,6 +46,8 @@ enum Opt {
         about = "Launches gui"
     )]
     Gui(GuiOptions),
+    #[structopt(name = "list", about = "Lists the events ruperf can count")]
+    List(ListOptions),
 }
 
 fn main() {
@@ -54,5 +58,6 @@ fn main() {
         Opt::Gui(x) => {
             run_gui(&x).unwrap();
         }
+        Opt::List(x) => run_list(x),
     }
 }
@@ -1,19 +1,45 @@
 //! # Stat driver.
 //! <p> Usage: <em> ruperf stat [COMMAND] [ARGS] </em>
-//! Where COMMAND and ARGS are a shell command and it's arguments. </p>
+//! Where COMMAND and ARGS are a shell command and it's arguments.
+//! With <em>-p PID</em> or <em>-t TID</em>, running processes or threads
+//! are counted instead, and with <em>-a</em> or <em>-C CPUS</em> every
+//! process on those CPUs, until COMMAND exits or Ctrl-C is pressed.
+//! The processes and threads counted ones create are counted too,
+//! unless <em>--no-inherit</em> is given. ruperf exits with COMMAND's
+//! exit status, or 128 plus the signal that killed it, and passes on
+//! SIGINT and SIGTERM to it, still printing what was counted. </p>
 
 extern crate structopt;
+use crate::bindings::*;
 use crate::event::open::*;
 use crate::utils::ParseError;
 use os_pipe::pipe;
+use std::collections::BTreeMap;
+use std::fs;
 use std::io::prelude::*;
-use std::os::unix::process::CommandExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
 use std::process::Command;
 use std::str::{self, FromStr};
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
-/// Supported events
+mod interval;
+mod metric;
+mod output;
+mod stats;
+mod topdown;
+
+use interval::Intervals;
+use metric::{Counts, Metric};
+use output::{Count, Format, Label, Output, Value};
+use stats::Stats;
+use topdown::Topdown;
+
+/// Named presets for commonly used events.
+/// Anything else can be counted through an `EventSpec`.
 #[derive(Debug, Copy, Clone)]
 pub enum StatEvent {
     Cycles,
@@ -26,6 +52,36 @@ pub enum StatEvent {
     L1ICacheReadMiss,
 }
 
+impl StatEvent {
+    /// Every preset, in the order they're counted by default.
+    pub const ALL: [StatEvent; 8] = [
+        StatEvent::Cycles,
+        StatEvent::Instructions,
+        StatEvent::TaskClock,
+        StatEvent::ContextSwitches,
+        StatEvent::L1DCacheRead,
+        StatEvent::L1DCacheWrite,
+        StatEvent::L1DCacheReadMiss,
+        StatEvent::L1ICacheReadMiss,
+    ];
+
+    /// The name `ruperf list` gives it, as perf does, which
+    /// machine readable output uses. Text output uses the
+    /// prose of `to_string`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StatEvent::Cycles => "cycles",
+            StatEvent::Instructions => "instructions",
+            StatEvent::TaskClock => "task-clock",
+            StatEvent::ContextSwitches => "context-switches",
+            StatEvent::L1DCacheRead => "L1-dcache-loads",
+            StatEvent::L1DCacheWrite => "L1-dcache-stores",
+            StatEvent::L1DCacheReadMiss => "L1-dcache-load-misses",
+            StatEvent::L1ICacheReadMiss => "L1-icache-load-misses",
+        }
+    }
+}
+
 /// Match on each supported event to parse from command line
 impl FromStr for StatEvent {
     type Err = ParseError;
@@ -44,6 +100,106 @@ impl FromStr for StatEvent {
     }
 }
 
+/// The spec each preset stands for.
+impl From<StatEvent> for EventSpec {
+    fn from(event: StatEvent) -> Self {
+        let spec = match event {
+            StatEvent::Cycles => EventSpec::hardware(perf_hw_id_PERF_COUNT_HW_CPU_CYCLES),
+            StatEvent::Instructions => EventSpec::hardware(perf_hw_id_PERF_COUNT_HW_INSTRUCTIONS),
+            StatEvent::TaskClock => EventSpec::software(perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK),
+            StatEvent::ContextSwitches => {
+                EventSpec::software(perf_sw_ids_PERF_COUNT_SW_CONTEXT_SWITCHES)
+            }
+            StatEvent::L1DCacheRead => EventSpec::cache(
+                perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
+                perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
+                perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_ACCESS,
+            ),
+            StatEvent::L1DCacheWrite => EventSpec::cache(
+                perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
+                perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_WRITE,
+                perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_ACCESS,
+            ),
+            StatEvent::L1DCacheReadMiss => EventSpec::cache(
+                perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
+                perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
+                perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_MISS,
+            ),
+            StatEvent::L1ICacheReadMiss => EventSpec::cache(
+                perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1I,
+                perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
+                perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_MISS,
+            ),
+        };
+        spec.name(event.name())
+    }
+}
+
+/// An event group: either a single event, or a perf-style
+/// `{cycles,instructions}` group whose members are
+/// scheduled and counted together.
+#[derive(Debug, Clone)]
+pub struct StatGroup(pub Vec<EventSpec>);
+
+/// The events and groups given to one `-e`, in perf's
+/// event syntax, e.g. `cycles:u,{instructions,branches}`.
+#[derive(Debug, Clone)]
+pub struct EventList(pub Vec<StatGroup>);
+
+impl FromStr for EventList {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_events(s) {
+            Ok(groups) => Ok(EventList(groups.into_iter().map(StatGroup).collect())),
+            Err(err) => Err(ParseError::Syntax {
+                input: s.to_string(),
+                err,
+            }),
+        }
+    }
+}
+
+/// The CPUs given to `-C`, e.g. `0-3,8`.
+#[derive(Debug, Clone)]
+pub struct CpuList(pub Vec<i32>);
+
+impl FromStr for CpuList {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_cpu_list(s) {
+            Some(cpus) => Ok(CpuList(cpus)),
+            None => Err(ParseError::CpuList(s.to_string())),
+        }
+    }
+}
+
+/// How long `--duration` counts for, e.g. `5s`, `500ms`
+/// or `2m`. A bare number is in seconds.
+#[derive(Debug, Copy, Clone)]
+pub struct Span(pub Duration);
+
+impl FromStr for Span {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let scale = match unit {
+            "ms" => 1e-3,
+            "" | "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return Err(ParseError::Duration(s.to_string())),
+        };
+        match number.parse::<f64>() {
+            // Up to about 30 years, which Duration can hold.
+            Ok(n) if n > 0.0 && n * scale < 1e9 => Ok(Span(Duration::from_secs_f64(n * scale))),
+            _ => Err(ParseError::Duration(s.to_string())),
+        }
+    }
+}
+
 /// Match on each supported event to parse from command line.
 /// Note that the context-switches event runs in kernel mode
 /// and requires a perf_event_paranoid setting < 1.
@@ -64,50 +220,628 @@ impl ToString for StatEvent {
 
 /// Configuration settings for running stat. A program to profile is a required
 /// argument. Default events will run on that program if no events are
-/// specified. Specify events using the flag `-e or --event`. See `./ruperf stat
+/// specified. Specify events using the flag `-e or --event` in perf's event
+/// syntax, and group events with `-e {cycles,instructions}`. See `./ruperf stat
 /// --help' for more information.
 #[derive(Debug, StructOpt)]
 pub struct StatOptions {
-    #[structopt(short, long, help = "Event to collect", number_of_values = 1)]
-    pub event: Vec<StatEvent>,
+    #[structopt(
+        short,
+        long,
+        help = "Events to collect, e.g. cycles:u,{instructions,cache-misses}",
+        number_of_values = 1
+    )]
+    pub event: Vec<EventList>,
+
+    #[structopt(
+        short = "M",
+        long,
+        help = "Count and show these comma separated metric groups or metrics, \
+                from this CPU's pmu-events tables",
+        use_delimiter = true,
+        number_of_values = 1
+    )]
+    pub metrics: Vec<String>,
+
+    #[structopt(
+        long,
+        help = "Show how the CPU's pipeline slots were spent: retiring, \
+                bad speculation, frontend bound or backend bound"
+    )]
+    pub topdown: bool,
+
+    #[structopt(
+        long,
+        value_name = "LEVEL",
+        help = "How deep to split up the --topdown areas, 1 or 2",
+        requires = "topdown"
+    )]
+    pub td_level: Option<usize>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Count the processes with these comma separated PIDs",
+        use_delimiter = true,
+        number_of_values = 1
+    )]
+    pub pid: Vec<i32>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Count the threads with these comma separated TIDs",
+        use_delimiter = true,
+        number_of_values = 1
+    )]
+    pub tid: Vec<i32>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Count every process on every online CPU",
+        conflicts_with_all = &["pid", "tid"]
+    )]
+    pub all_cpus: bool,
+
+    #[structopt(
+        short = "C",
+        long,
+        help = "Count every process on these CPUs only, e.g. 0-3,8",
+        conflicts_with_all = &["pid", "tid"]
+    )]
+    pub cpu: Option<CpuList>,
+
+    #[structopt(
+        short = "A",
+        long,
+        help = "Show the counts on each CPU, rather than their total"
+    )]
+    pub no_aggr: bool,
+
+    #[structopt(
+        long,
+        help = "Don't count the processes and threads the counted ones create"
+    )]
+    pub no_inherit: bool,
+
+    #[structopt(
+        long,
+        help = "Show the counts of each thread, rather than their total",
+        conflicts_with_all = &["all-cpus", "cpu"]
+    )]
+    pub per_thread: bool,
+
+    #[structopt(
+        long,
+        help = "Count for this long, e.g. 5s or 500ms, rather than until Ctrl-C, \
+                with -p, -t, -a or -C",
+        conflicts_with = "command"
+    )]
+    pub duration: Option<Span>,
+
+    #[structopt(
+        short = "D",
+        long,
+        value_name = "MS",
+        help = "Wait this many milliseconds before starting to count"
+    )]
+    pub delay: Option<u64>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Run the command this many times, showing the mean and spread of each count",
+        conflicts_with_all = &["pid", "tid", "all-cpus", "cpu", "per-thread"]
+    )]
+    pub repeat: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "Run the command this many times first, without showing their counts",
+        conflicts_with_all = &["pid", "tid", "all-cpus", "cpu"]
+    )]
+    pub warmup: Option<usize>,
+
+    #[structopt(
+        short = "I",
+        long,
+        help = "Print the counts every this many milliseconds",
+        conflicts_with_all = &["repeat", "warmup", "per-thread"]
+    )]
+    pub interval_print: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Stop after printing this many intervals",
+        requires = "interval-print"
+    )]
+    pub interval_count: Option<usize>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Print each count as a JSON object, one per line",
+        conflicts_with = "field-separator"
+    )]
+    pub json: bool,
+
+    #[structopt(
+        short = "x",
+        long,
+        value_name = "SEP",
+        help = "Print each count as fields separated by SEP, like perf stat -x"
+    )]
+    pub field_separator: Option<String>,
+
+    #[structopt(
+        short,
+        long,
+        value_name = "FILE",
+        help = "Print the counts to FILE rather than stdout"
+    )]
+    pub output: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Add to the end of the -o file rather than replacing it",
+        requires = "output"
+    )]
+    pub append: bool,
 
     // Allows multiple arguments to be passed, collects everything remaining on
-    // the command line
-    #[structopt(required = true, help = "Command to run")]
+    // the command line. Optional when attaching with `-p`, `-t`, `-a` or `-C`,
+    // when it just sets how long to count for.
+    #[structopt(
+        required_unless_one = &["pid", "tid", "all-cpus", "cpu"],
+        help = "Command to run"
+    )]
     pub command: Vec<String>,
 }
 
+impl StatOptions {
+    /// Are we counting every process on some CPUs?
+    fn system_wide(&self) -> bool {
+        self.all_cpus || self.cpu.is_some()
+    }
+    /// Are the threads a launched command creates to be
+    /// counted, and shown, each on their own?
+    fn inherit_stat(&self) -> bool {
+        self.per_thread && !self.no_inherit && self.pid.is_empty() && self.tid.is_empty()
+    }
+}
+
+/// Where one copy of an event group is opened: on a thread,
+/// or on every thread running on a CPU. -1 means any.
+#[derive(Debug, Copy, Clone)]
+struct Target {
+    pid: i32,
+    cpu: i32,
+}
+
+/// Counts for one event group, opened once on each
+/// target counted. `start` and `stop` hold a reading per
+/// target, with one value per group member, in member order.
+/// `groups` is `None` if the kernel or CPU can't count
+/// one of the `events`.
 struct Counter {
-    event: Event,
-    start: isize,
-    stop: isize,
+    events: StatGroup,
+    groups: Option<Vec<EventGroup>>,
+    /// Where each of the `groups` is counting.
+    targets: Vec<Target>,
+    start: Vec<Reading>,
+    stop: Vec<Reading>,
+    /// Readings as of the last interval printed, with `-I`.
+    last: Vec<Reading>,
+    /// What each inherited thread counted, by TID, as
+    /// it exited, with `inherit_stat`. Their counts are
+    /// also in those of the target they were created by.
+    exited: BTreeMap<i32, Reading>,
 }
 
 impl Counter {
-    /// Generate list of timers for a given `pid`.
-    pub fn counters(options: &mut StatOptions, pid: i32) -> Vec<Counter> {
+    /// Generate list of timers for the given targets.
+    /// Threads that exit before they can be counted are skipped.
+    fn counters(options: &mut StatOptions, targets: &[Target]) -> Result<Vec<Counter>, EventErr> {
         let mut counters: Vec<Counter> = Vec::new();
 
         if options.event.is_empty() {
-            options.event.push(StatEvent::Cycles);
-            options.event.push(StatEvent::Instructions);
-            options.event.push(StatEvent::TaskClock);
-            options.event.push(StatEvent::ContextSwitches);
-            options.event.push(StatEvent::L1DCacheRead);
-            options.event.push(StatEvent::L1DCacheWrite);
-            options.event.push(StatEvent::L1DCacheReadMiss);
-            options.event.push(StatEvent::L1ICacheReadMiss);
+            for event in &StatEvent::ALL {
+                options
+                    .event
+                    .push(EventList(vec![StatGroup(vec![(*event).into()])]));
+            }
+        }
+
+        // Like perf, count what the counted tasks go on to create,
+        // unless asked not to. CPUs count every task on them anyway.
+        let inherit = !options.no_inherit && !options.system_wide();
+        let inherit_stat = options.inherit_stat();
+        let mut events: Vec<StatGroup> = Vec::new();
+        for group in options.event.iter().flat_map(|list| &list.0) {
+            // The kernel only reports what an exiting thread counted
+            // for events without siblings, so groups are split up.
+            if inherit_stat {
+                events.extend(group.0.iter().map(|spec| StatGroup(vec![spec.clone()])));
+            } else {
+                events.push(group.clone());
+            }
         }
 
-        for event in &options.event {
+        for events in events {
+            let mut specs: Vec<EventSpec> = events
+                .0
+                .iter()
+                .map(|spec| spec.clone().inherit(inherit))
+                .collect();
+            if inherit_stat {
+                for spec in specs.iter_mut() {
+                    spec.inherit_stat = true;
+                    spec.comm = true;
+                    // Sampling reads per thread, though nothing is
+                    // sampled, stops the kernel swapping a thread's
+                    // counters with its parent's as they take turns on a
+                    // CPU, which would leave its exit counts unreported.
+                    // Older kernels than 6.12 refuse to open these,
+                    // with EINVAL, which `run_command` explains.
+                    spec.sample_type = perf_event_sample_format_PERF_SAMPLE_READ
+                        | perf_event_sample_format_PERF_SAMPLE_TID;
+                }
+            }
+            let mut groups = Vec::new();
+            let mut opened = Vec::new();
+            let mut supported = true;
+            for target in targets {
+                match EventGroup::on_cpu(&specs, Some(target.pid), target.cpu) {
+                    Ok(group) => {
+                        groups.push(group);
+                        opened.push(*target);
+                    }
+                    // Like perf, carry on without events this
+                    // machine doesn't have, and say so at the end.
+                    Err(e) if e.is_unsupported() => {
+                        supported = false;
+                        break;
+                    }
+                    Err(e) if exited(&e) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
             counters.push(Counter {
-                event: Event::new(*event, Some(pid)),
-                start: 0,
-                stop: 0,
+                events,
+                groups: if supported { Some(groups) } else { None },
+                targets: opened,
+                start: Vec::new(),
+                stop: Vec::new(),
+                last: Vec::new(),
+                exited: BTreeMap::new(),
             });
         }
 
-        counters
+        Ok(counters)
+    }
+    /// Start counting on every target.
+    fn start(&mut self) -> Result<(), SysErr> {
+        if let Some(groups) = &self.groups {
+            self.start = groups
+                .iter()
+                .map(|g| g.start_counter())
+                .collect::<Result<_, _>>()?;
+            self.last = self.start.clone();
+        }
+        Ok(())
+    }
+    /// What each target counted since the last
+    /// interval, or since counting started.
+    fn interval(&mut self) -> Result<Vec<Reading>, SysErr> {
+        let groups = match &self.groups {
+            Some(groups) => groups,
+            None => return Ok(Vec::new()),
+        };
+        let now: Vec<Reading> = groups.iter().map(|g| g.read()).collect::<Result<_, _>>()?;
+        let deltas = now
+            .iter()
+            .zip(&self.last)
+            .map(|(now, last)| now.since(last))
+            .collect();
+        self.last = now;
+        Ok(deltas)
+    }
+    /// Stop counting on every target. Counters of threads
+    /// that have exited keep what they counted until then.
+    fn stop(&mut self) -> Result<(), SysErr> {
+        if let Some(groups) = &self.groups {
+            self.stop = groups
+                .iter()
+                .map(|g| g.stop_counter())
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(())
+    }
+    /// What every target counted, totalled.
+    fn reading(&self) -> Reading {
+        let mut total = Reading::default();
+        for (start, stop) in self.start.iter().zip(&self.stop) {
+            total.add(&stop.since(start));
+        }
+        total
+    }
+    /// What each thread counted: first each target, less what
+    /// the threads it created that have since exited counted, then
+    /// each of those. Threads still running when counting stopped
+    /// are part of their creator's count.
+    fn threads(&self) -> Vec<(i32, Reading)> {
+        let mut threads: Vec<(i32, Reading)> = Vec::new();
+        for (i, target) in self.targets.iter().enumerate() {
+            let reading = self.stop[i].since(&self.start[i]);
+            match threads.iter_mut().find(|(tid, _)| *tid == target.pid) {
+                Some((_, total)) => total.add(&reading),
+                None => threads.push((target.pid, reading)),
+            }
+        }
+        if let Some((_, first)) = threads.first_mut() {
+            let mut exited = Reading::default();
+            for reading in self.exited.values() {
+                exited.add(reading);
+            }
+            *first = first.since(&exited);
+        }
+        threads.extend(self.exited.iter().map(|(tid, r)| (*tid, r.clone())));
+        threads
+    }
+}
+
+/// Data pages in each ring buffer exiting threads' counts are written to.
+const THREAD_PAGES: usize = 16;
+
+/// The ring buffers, one per CPU, that the counters of a launched
+/// command's threads are written to as they exit, with `--per-thread`.
+struct Threads {
+    rings: Vec<RingBuffer>,
+    /// The command.
+    pid: i32,
+    /// The latest name of each thread, from `PERF_RECORD_COMM`.
+    comms: BTreeMap<i32, String>,
+    /// Records the kernel had to drop, for want of room.
+    lost: u64,
+}
+
+impl Threads {
+    /// Map a ring buffer for the first counter on each CPU,
+    /// and have every other counter on that CPU write to it.
+    /// `comm` names `pid`, the command, until it's renamed.
+    fn new(counters: &[Counter], pid: i32, comm: &str) -> Result<Self, SysErr> {
+        let mut rings = Vec::new();
+        let mut outputs: Vec<(i32, &EventGroup)> = Vec::new();
+        for counter in counters {
+            let groups = match &counter.groups {
+                Some(groups) => groups,
+                None => continue,
+            };
+            for (group, target) in groups.iter().zip(&counter.targets) {
+                match outputs.iter().find(|(cpu, _)| *cpu == target.cpu) {
+                    Some((_, output)) => group.leader().fd.set_output(&output.leader().fd)?,
+                    None => {
+                        rings.push(RingBuffer::new(&group.leader().fd, THREAD_PAGES)?);
+                        outputs.push((target.cpu, group));
+                    }
+                }
+            }
+        }
+        let mut comms = BTreeMap::new();
+        comms.insert(pid, comm.to_string());
+        Ok(Threads {
+            rings,
+            pid,
+            comms,
+            lost: 0,
+        })
+    }
+    /// Read every waiting record, adding each exited
+    /// thread's counts to the counter they're from.
+    fn drain(&mut self, counters: &mut [Counter]) {
+        for ring in self.rings.iter_mut() {
+            for record in ring.records() {
+                match record {
+                    Record::Read { pid, tid, reading } => {
+                        let (pid, tid) = (pid as i32, tid as i32);
+                        // Threads that never exec share their process'
+                        // name, or failing that the command's.
+                        if !self.comms.contains_key(&tid) {
+                            let comm = match self.comms.get(&pid) {
+                                Some(comm) => Some(comm),
+                                None => self.comms.get(&self.pid),
+                            };
+                            if let Some(comm) = comm.cloned() {
+                                self.comms.insert(tid, comm);
+                            }
+                        }
+                        for counter in counters.iter_mut() {
+                            let groups = match &counter.groups {
+                                Some(groups) => groups,
+                                None => continue,
+                            };
+                            if let Some(r) =
+                                groups.iter().find_map(|g| g.member_order(reading.clone()))
+                            {
+                                counter.exited.entry(tid).or_default().add(&r);
+                                break;
+                            }
+                        }
+                    }
+                    Record::Comm { tid, comm, .. } => {
+                        self.comms.insert(tid as i32, comm);
+                    }
+                    Record::Lost { lost, .. } => self.lost += lost,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Did opening an event fail because its thread has gone?
+fn exited(err: &EventErr) -> bool {
+    matches!(err.sys().and_then(|e| e.errno()), Some(libc::ESRCH))
+}
+
+/// The threads of each process in `pids`, from `/proc/PID/task`,
+/// followed by `tids`. Fails naming the first that doesn't exist.
+fn threads(pids: &[i32], tids: &[i32]) -> Result<Vec<i32>, String> {
+    let mut threads = Vec::new();
+    for pid in pids {
+        let tasks = match fs::read_dir(format!("/proc/{}/task", pid)) {
+            Ok(tasks) => tasks,
+            Err(_) => return Err(format!("no process with PID {}", pid)),
+        };
+        let mut tasks: Vec<i32> = tasks
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str()?.parse().ok())
+            .collect();
+        tasks.sort_unstable();
+        threads.extend(tasks);
+    }
+    for tid in tids {
+        if !alive(*tid) {
+            return Err(format!("no thread with TID {}", tid));
+        }
+        threads.push(*tid);
+    }
+    Ok(threads)
+}
+
+/// Where to count, from `-a`, `-C`, `-p` and `-t`: every process
+/// on each CPU asked for, or else each thread on any CPU.
+/// Empty if we're to count a command we launch ourselves.
+fn targets(options: &StatOptions) -> Result<Vec<Target>, String> {
+    if !options.system_wide() {
+        let threads = threads(&options.pid, &options.tid)?;
+        return Ok(threads.iter().map(|&pid| Target { pid, cpu: -1 }).collect());
+    }
+    let online = match online_cpus() {
+        Ok(online) => online,
+        Err(e) => return Err(format!("could not list online CPUs: {}", e)),
+    };
+    let cpus = match &options.cpu {
+        Some(list) => list.0.clone(),
+        None => online.clone(),
+    };
+    if let Some(cpu) = cpus.iter().find(|cpu| !online.contains(cpu)) {
+        return Err(format!("CPU {} is not online", cpu));
+    }
+    Ok(cpus.iter().map(|&cpu| Target { pid: -1, cpu }).collect())
+}
+
+/// Is the thread `tid` still running? Zombies,
+/// waiting for their parent to reap them, are not.
+fn alive(tid: i32) -> bool {
+    let stat = match fs::read_to_string(format!("/proc/{}/stat", tid)) {
+        Ok(stat) => stat,
+        Err(_) => return false,
+    };
+    // The state follows the command name, which is in
+    // parentheses and may itself contain spaces or ')'.
+    match stat
+        .rfind(')')
+        .and_then(|i| stat[i + 1..].split_whitespace().next())
+    {
+        Some(state) => state != "Z" && state != "X",
+        None => false,
+    }
+}
+
+/// Set by SIGINT (Ctrl-C) or SIGTERM, once `catch_signals` is called.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The last SIGINT or SIGTERM caught that
+/// wasn't also sent to the command, until passed on to it.
+static PENDING: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn interrupt(signal: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    // Ctrl-C is sent by the kernel to the whole foreground
+    // process group, the command included. A SIGINT from
+    // `kill`, or any SIGTERM, was only sent to us.
+    let from_tty = unsafe { (*info).si_code } == libc::SI_KERNEL;
+    if signal == libc::SIGTERM || !from_tty {
+        PENDING.store(signal, Ordering::SeqCst);
+    }
+}
+
+/// Stop counting on SIGINT or SIGTERM, rather than
+/// dying, so what was counted so far is still printed.
+fn catch_signals() {
+    for signal in &[libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = interrupt
+                as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void)
+                as libc::sighandler_t;
+            action.sa_flags = libc::SA_SIGINFO;
+            libc::sigaction(*signal, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+/// Pass on a SIGINT or SIGTERM sent only to us, if any,
+/// to the command `pid`, so it stops too.
+fn forward_signal(pid: i32) {
+    let signal = PENDING.swap(0, Ordering::SeqCst);
+    if signal != 0 {
+        unsafe { libc::kill(pid, signal) };
+    }
+}
+
+/// A command's wait status as a shell reports it: its exit
+/// code, or 128 plus the number of the signal that killed it.
+fn exit_code(status: libc::c_int) -> i32 {
+    if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        libc::WEXITSTATUS(status)
+    }
+}
+
+/// Start every counter.
+fn start_counters(counters: &mut [Counter]) -> Result<(), SysErr> {
+    counters.iter_mut().try_for_each(Counter::start)
+}
+
+/// Stop every counter.
+fn stop_counters(counters: &mut [Counter]) -> Result<(), SysErr> {
+    counters.iter_mut().try_for_each(Counter::stop)
+}
+
+/// Report that counting failed part way, and exit,
+/// killing the command `pid` we ran, if any, first.
+fn counting_failed(err: SysErr, pid: Option<i32>, system_wide: bool) -> ! {
+    if let Some(pid) = pid {
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+    }
+    report_error(&err.into(), system_wide);
+    std::process::exit(1);
+}
+
+/// Print why an event could not be opened, along with
+/// a hint on how to fix it where we have one.
+fn report_error(err: &EventErr, system_wide: bool) {
+    eprintln!("Error: {}", err);
+    let errno = err.sys().and_then(|e| e.errno());
+    if system_wide && matches!(errno, Some(libc::EACCES) | Some(libc::EPERM)) {
+        let paranoid = fs::read_to_string("/proc/sys/kernel/perf_event_paranoid");
+        eprintln!(
+            "\nCounting every process on a CPU, with -a or -C, needs \
+             perf_event_paranoid to be 0 or less (it is {}),\n\
+             or ruperf to have CAP_PERFMON (CAP_SYS_ADMIN before Linux 5.8).",
+            paranoid.as_deref().map_or("unknown", str::trim)
+        );
+        return;
+    }
+    if let Some(hint) = err.sys().and_then(|e| e.hint()) {
+        eprintln!("\n{}", hint);
     }
 }
 
@@ -133,23 +867,254 @@ pub fn launch_stat_process(
                 .write_all(&instant.elapsed().as_nanos().to_ne_bytes())
                 .expect("Could not write start time");
             child_writer.flush().unwrap();
-            drop(child_writer);
 
+            // The writer is closed on exec, so the parent only
+            // reads more if the command couldn't be run: why.
             let e = comm.exec();
-            panic!("child command failed: {}", e);
+            let errno = e.raw_os_error().unwrap_or(0);
+            let _ = child_writer.write_all(&errno.to_ne_bytes());
+            // Like a shell, 127 for a command that isn't there.
+            unsafe { libc::_exit(127) };
         }
         pid_child => pid_child,
     }
 }
 
-/// Run perf stat on the given command and event combinations.
-/// Currently starts and stops a cycles timer in serial for each event specified.
-pub fn run_stat(options: StatOptions) {
-    // In future rather than starting and stopping counter
-    // in series for each event, events will have the ability
-    // to be added in groups that will coordinate their timing.
+/// Run perf stat on the given command and event combinations,
+/// or on the processes and threads given with `-p` and `-t`.
+/// Each event group is started and stopped as a unit, so members
+/// of the same group are always counted over the same window.
+pub fn run_stat(mut options: StatOptions) {
+    if options.no_aggr && !options.system_wide() {
+        eprintln!("Error: -A only applies to counting CPUs, with -a or -C");
+        std::process::exit(1);
+    }
+    if options.repeat == Some(0) {
+        eprintln!("Error: -r needs at least 1 run");
+        std::process::exit(1);
+    }
+    if matches!(options.interval_print, Some(ms) if ms < 10) {
+        eprintln!("Error: -I needs an interval of at least 10ms");
+        std::process::exit(1);
+    }
+    let mut metrics = metric::builtin();
+    if !options.metrics.is_empty() {
+        match vendor_metrics(&mut options) {
+            Ok(vendor) => metrics.extend(vendor),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let topdown = if options.topdown {
+        match topdown(&mut options) {
+            Ok(topdown) => Some(topdown),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let targets = match targets(&options) {
+        Ok(targets) => targets,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let format = match (&options.field_separator, options.json) {
+        (Some(sep), _) => Format::Csv(sep.clone()),
+        (None, true) => Format::Json,
+        (None, false) => Format::Text,
+    };
+    let mut out = match Output::new(format, options.output.as_deref(), options.append) {
+        Ok(out) => out.with_metrics(metrics).with_topdown(topdown),
+        Err(e) => {
+            let path = options.output.as_deref().unwrap_or_default();
+            eprintln!("Error: could not open '{}': {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let code = if targets.is_empty() {
+        stat_command(options, &mut out)
+    } else {
+        stat_attached(options, targets, &mut out)
+    };
+    // Exit as the command did, so scripts can tell if it failed.
+    drop(out);
+    std::process::exit(code);
+}
+
+/// The metrics of the `-M` groups, from this CPU's pmu-events
+/// tables, adding a group of the events each needs to those counted.
+fn vendor_metrics(options: &mut StatOptions) -> Result<Vec<Metric>, String> {
+    let tables = PmuEvents::for_this_cpu().ok_or_else(|| {
+        format!(
+            "-M needs pmu-events tables for this CPU; set {} to perf's pmu-events/arch",
+            PMU_EVENTS_VAR
+        )
+    })?;
+    let mut metrics: Vec<Metric> = Vec::new();
+    for name in &options.metrics {
+        let group = tables.metric_group(name);
+        if group.is_empty() {
+            return Err(format!("unknown metric or metric group '{}'", name));
+        }
+        for vendor in group {
+            if metrics.iter().any(|m| m.name == vendor.name) {
+                continue;
+            }
+            // Metric groups may have metrics using
+            // syntax we can't work out yet; skip those.
+            let metric = match Metric::vendor(vendor, tables) {
+                Ok(metric) => metric,
+                Err(e) => {
+                    eprintln!("Warning: skipping metric '{}': {}", vendor.name, e);
+                    continue;
+                }
+            };
+            let mut specs = Vec::new();
+            for event in metric.events() {
+                match tables.event(event).or_else(|| lookup(event)) {
+                    Some(spec) => specs.push(spec),
+                    None => {
+                        return Err(format!(
+                            "metric '{}' needs the unknown event '{}'",
+                            vendor.name, event
+                        ))
+                    }
+                }
+            }
+            // Like perf, count each metric's events as a group,
+            // so they're all counted over the same time.
+            options.event.push(EventList(vec![StatGroup(specs)]));
+            metrics.push(metric);
+        }
+    }
+    Ok(metrics)
+}
+
+/// The topdown areas to show, down to `--td-level`, adding
+/// a group of the events they need to those counted.
+fn topdown(options: &mut StatOptions) -> Result<Topdown, String> {
+    let level = options.td_level.unwrap_or(1);
+    if level != 1 && level != 2 {
+        return Err("--td-level must be 1 or 2".to_string());
+    }
+    let cpu = Pmu::open(Path::new(EVENT_SOURCE), "cpu");
+    let (topdown, events) = Topdown::new(cpu.as_ref(), level)?;
+    options.event.push(EventList(vec![StatGroup(events)]));
+    Ok(topdown)
+}
+
+/// Exit as a shell would when `command` couldn't be run: with 127
+/// if there's no such command, or 126 if it can't be run.
+fn could_not_run(command: &str, e: &std::io::Error) -> ! {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        eprintln!("Error: '{}': command not found", command);
+        std::process::exit(127);
+    }
+    eprintln!("Error: could not run '{}': {}", command, e);
+    std::process::exit(126);
+}
+
+/// Count a command we launch ourselves. Returns
+/// the exit code of its last run, to exit with.
+fn stat_command(options: StatOptions, out: &mut Output) -> i32 {
     let mut options = options;
+    catch_signals();
+    for _ in 0..options.warmup.unwrap_or(0) {
+        let run = run_command(&mut options, out);
+        if run.interrupted {
+            return run.status;
+        }
+    }
+    let repeat = options.repeat.unwrap_or(1);
+    if repeat > 1 {
+        let mut runs = Runs::default();
+        let mut status = 0;
+        for _ in 0..repeat {
+            let run = run_command(&mut options, out);
+            runs.add(&run.counters, run.t);
+            status = run.status;
+            // Show the runs so far.
+            if run.interrupted {
+                break;
+            }
+        }
+        out.title(&format!(
+            "Performance counter stats for '{}' ({} runs):",
+            options.command[0],
+            runs.elapsed.values.len()
+        ));
+        runs.print(out);
+        return status;
+    }
+
+    if let Some(ms) = options.interval_print {
+        out.title(&format!(
+            "Performance counter stats for '{}', every {} ms:",
+            options.command[0], ms
+        ));
+    }
+    let Run {
+        pid,
+        counters,
+        t,
+        threads,
+        status,
+        ..
+    } = run_command(&mut options, out);
+    if options.interval_print.is_some() {
+        // Every count has been printed, interval by interval.
+        return status;
+    }
+    out.title(&format!(
+        "Performance counter stats for '{}:'",
+        options.command.get(0).unwrap()
+    ));
+    match &threads {
+        // With --no-inherit, only the command's own thread was counted.
+        None if options.per_thread => {
+            let mut comms = BTreeMap::new();
+            comms.insert(pid, options.command[0].clone());
+            print_counters(out, &counters, t, &Split::Thread(&comms));
+        }
+        None => print_counters(out, &counters, t, &Split::Total),
+        Some(threads) => {
+            print_counters(out, &counters, t, &Split::Thread(&threads.comms));
+            if threads.lost > 0 {
+                eprintln!(
+                    "\nWarning: the counts of {} exited threads were lost",
+                    threads.lost
+                );
+            }
+        }
+    }
+    status
+}
+
+/// What one run of a command counted, and how it ended.
+struct Run {
+    /// The command's PID.
+    pid: i32,
+    counters: Vec<Counter>,
+    /// How many nanoseconds it ran for.
+    t: u128,
+    /// With `--per-thread`, what its threads counted as they exited.
+    threads: Option<Threads>,
+    /// Its exit code, as `exit_code` gives it.
+    status: i32,
+    /// Whether we were sent SIGINT or SIGTERM while it ran.
+    interrupted: bool,
+}
 
+/// Launch the command and count it until it exits. If it
+/// can't be run, say why and exit as a shell would.
+fn run_command(options: &mut StatOptions, out: &mut Output) -> Run {
     let (reader, mut writer) = pipe().unwrap();
     let (mut parent_reader, parent_writer) = pipe().unwrap();
     let child_reader = reader.try_clone().unwrap();
@@ -162,49 +1127,641 @@ pub fn run_stat(options: StatOptions) {
         child_reader,
         child_writer,
     );
-    let mut counters = Counter::counters(&mut options, pid_child);
+    // Only the child may hold a writer, so we see the pipe close.
+    drop(parent_writer);
+    let abort = |e: &dyn std::fmt::Display| -> ! {
+        // The child is still waiting to be told to start.
+        unsafe {
+            libc::kill(pid_child, libc::SIGKILL);
+            libc::waitpid(pid_child, std::ptr::null_mut(), 0);
+        }
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    };
+    // Inherited counters can only have what their exiting
+    // threads counted written out when opened on each CPU.
+    let targets: Vec<Target> = if options.inherit_stat() {
+        match online_cpus() {
+            Ok(cpus) => cpus
+                .iter()
+                .map(|&cpu| Target {
+                    pid: pid_child,
+                    cpu,
+                })
+                .collect(),
+            Err(e) => abort(&format!("could not list online CPUs: {}", e)),
+        }
+    } else {
+        vec![Target {
+            pid: pid_child,
+            cpu: -1,
+        }]
+    };
+    let mut counters = match Counter::counters(options, &targets) {
+        Ok(counters) => counters,
+        Err(e) => {
+            unsafe {
+                libc::kill(pid_child, libc::SIGKILL);
+                libc::waitpid(pid_child, std::ptr::null_mut(), 0);
+            }
+            let errno = e.sys().and_then(|e| e.errno());
+            if options.inherit_stat() && errno == Some(libc::EINVAL) {
+                eprintln!(
+                    "Error: --per-thread on a command ruperf runs needs Linux 6.12 \
+                     or later, to count threads separately as they exit.\n\
+                     Use --no-inherit to show just the command's own thread, \
+                     or -p with --per-thread to count it once it's running."
+                );
+                std::process::exit(1);
+            }
+            report_error(&e, false);
+            std::process::exit(1);
+        }
+    };
+    let mut threads = None;
+    if options.inherit_stat() {
+        match Threads::new(&counters, pid_child, &options.command[0]) {
+            Ok(t) => threads = Some(t),
+            Err(e) => abort(&e),
+        }
+    }
 
     let mut buffer: [u8; 16] = [0; 16];
     let mut status: libc::c_int = 0;
-    // Start all the counters.
-    for counter in counters.iter_mut() {
-        counter.start = counter.event.start_counter().unwrap();
+    // Start all the counters, unless they're to wait for --delay.
+    if options.delay.is_none() {
+        start_counters(&mut counters)
+            .unwrap_or_else(|e| counting_failed(e, Some(pid_child), false));
     }
     // Notify child we are ready.
     writer.write_all(&[1]).unwrap();
     writer.flush().unwrap();
     let nread = parent_reader.read(&mut buffer).unwrap();
-    let result = unsafe { libc::waitpid(pid_child, (&mut status) as *mut libc::c_int, 0) };
+    let mut errno = [0; 4];
+    if parent_reader.read_exact(&mut errno).is_ok() {
+        unsafe { libc::waitpid(pid_child, std::ptr::null_mut(), 0) };
+        let e = std::io::Error::from_raw_os_error(i32::from_ne_bytes(errno));
+        could_not_run(&options.command[0], &e);
+    }
+    let wake = interval::pidfd(pid_child);
+    let mut start_time = u128::from_ne_bytes(buffer);
+    if let Some(ms) = options.delay {
+        // Let the command get going first, unless it exits.
+        let wake: Vec<RawFd> = wake.iter().map(|f| f.as_raw_fd()).collect();
+        interval::poll_readable(&wake, Duration::from_millis(ms));
+        start_counters(&mut counters)
+            .unwrap_or_else(|e| counting_failed(e, Some(pid_child), false));
+        start_time = instant.elapsed().as_nanos();
+    }
+    let mut intervals = match options.interval_print {
+        Some(ms) => {
+            match Intervals::new(Duration::from_millis(ms), options.interval_count, false) {
+                Ok(intervals) => Some(intervals),
+                Err(e) => abort(&format!("could not start the interval timer: {}", e)),
+            }
+        }
+        None => None,
+    };
+    // Keep any ring buffers from filling up while we wait,
+    // and without a pidfd to wake us, notice the exit soon.
+    let poll = match (&threads, &wake) {
+        (None, Some(_)) => Duration::from_secs(1),
+        (None, None) => Duration::from_millis(10),
+        (Some(_), _) => Duration::from_millis(20),
+    };
+    let mut result = 0;
+    let exited = count_until(
+        out,
+        &mut counters,
+        &mut intervals,
+        wake.as_ref().map(|f| f.as_raw_fd()),
+        poll,
+        |counters| {
+            forward_signal(pid_child);
+            result = unsafe {
+                libc::waitpid(pid_child, (&mut status) as *mut libc::c_int, libc::WNOHANG)
+            };
+            if let Some(threads) = &mut threads {
+                threads.drain(counters);
+            }
+            result != 0
+        },
+    )
+    .unwrap_or_else(|e| counting_failed(e, Some(pid_child), false));
+    let mut code = exit_code(status);
+    if !exited {
+        // Enough intervals were printed. We stopped
+        // the command, so it didn't fail.
+        unsafe {
+            libc::kill(pid_child, libc::SIGTERM);
+            result = libc::waitpid(pid_child, (&mut status) as *mut libc::c_int, 0);
+        }
+        code = 0;
+    }
+    if let Some(threads) = &mut threads {
+        // Threads that exited as counting stopped, before the
+        // final readings, so theirs are part of them.
+        threads.drain(&mut counters);
+    }
     // Let's see how long they took.
     let stop_time: u128 = instant.elapsed().as_nanos();
-    for counter in counters.iter_mut() {
-        counter.stop = counter.event.stop_counter().unwrap();
-    }
-    let start_time = u128::from_ne_bytes(buffer);
+    stop_counters(&mut counters).unwrap_or_else(|e| counting_failed(e, None, false));
     let t = stop_time - start_time;
     assert_eq!(nread, 16);
     assert_eq!(result, pid_child);
     // Don't forget to drop the writer!
     drop(writer);
 
-    println!(
-        "Performance counter stats for '{}:'\n",
-        options.command.get(0).unwrap()
+    Run {
+        pid: pid_child,
+        counters,
+        t,
+        threads,
+        status: code,
+        interrupted: INTERRUPTED.swap(false, Ordering::SeqCst),
+    }
+}
+
+/// What every counter counted over repeated runs of a command.
+#[derive(Default)]
+struct Runs {
+    /// Each counter's events, and whether they could be counted.
+    events: Vec<(StatGroup, bool)>,
+    /// The scaled count of each event of each counter, per run.
+    counts: Vec<Vec<Stats>>,
+    /// How long each counter was enabled and running, over all runs.
+    times: Vec<Reading>,
+    /// How many nanoseconds each run took.
+    elapsed: Stats,
+}
+
+impl Runs {
+    /// Add the counts of one run.
+    fn add(&mut self, counters: &[Counter], t: u128) {
+        if self.events.is_empty() {
+            self.events = counters
+                .iter()
+                .map(|c| (c.events.clone(), c.groups.is_some()))
+                .collect();
+            self.counts = counters
+                .iter()
+                .map(|c| vec![Stats::default(); c.events.0.len()])
+                .collect();
+            self.times = vec![Reading::default(); counters.len()];
+        }
+        for (i, (counter, counts)) in counters.iter().zip(self.counts.iter_mut()).enumerate() {
+            let reading = counter.reading();
+            self.times[i].time_enabled += reading.time_enabled;
+            self.times[i].time_running += reading.time_running;
+            for (i, stats) in counts.iter_mut().enumerate() {
+                // Runs an event wasn't counted in are left out.
+                if let Some(count) = reading.scaled(i) {
+                    stats.push(count);
+                }
+            }
+        }
+        self.elapsed.push(t as u64);
+    }
+    /// Print the mean of each count, with its spread over the runs.
+    fn print(&self, out: &mut Output) {
+        let mut means = Counts::new(self.elapsed.mean());
+        for ((events, _), counts) in self.events.iter().zip(&self.counts) {
+            for (spec, stats) in events.0.iter().zip(counts) {
+                if !stats.values.is_empty() {
+                    means.add(spec, stats.mean());
+                }
+            }
+        }
+        for (i, (events, supported)) in self.events.iter().enumerate() {
+            if !supported {
+                print_unsupported(out, events, None);
+                continue;
+            }
+            for (spec, stats) in events.0.iter().zip(&self.counts[i]) {
+                let (value, metric) = if stats.values.is_empty() {
+                    (Value::NotCounted, None)
+                } else {
+                    (
+                        Value::Counted(stats.mean() as u64),
+                        means.metric(out.metrics(), spec),
+                    )
+                };
+                out.count(&Count {
+                    event: spec,
+                    interval: None,
+                    label: None,
+                    value,
+                    time_enabled: self.times[i].time_enabled,
+                    time_running: self.times[i].time_running,
+                    runs: Some(stats),
+                    metric,
+                });
+            }
+        }
+        print_topdown(out, &means, None, None);
+        out.elapsed(&self.elapsed);
+    }
+}
+
+/// Count running processes, threads or CPUs, until the command
+/// given exits, Ctrl-C is pressed, or every thread has exited.
+/// Returns the command's exit code, or 0 if there isn't one.
+fn stat_attached(options: StatOptions, targets: Vec<Target>, out: &mut Output) -> i32 {
+    let mut options = options;
+    let mut counters = match Counter::counters(&mut options, &targets) {
+        Ok(counters) => counters,
+        Err(e) => {
+            report_error(&e, options.system_wide());
+            std::process::exit(1);
+        }
+    };
+    if counters
+        .iter()
+        .any(|c| matches!(&c.groups, Some(groups) if groups.is_empty()))
+    {
+        eprintln!("Error: every thread exited before it could be counted");
+        std::process::exit(1);
+    }
+
+    catch_signals();
+    // Name the threads while they're still around to ask.
+    let comms: BTreeMap<i32, String> = targets
+        .iter()
+        .filter_map(|t| {
+            let comm = fs::read_to_string(format!("/proc/{}/comm", t.pid)).ok()?;
+            Some((t.pid, comm.trim_end().to_string()))
+        })
+        .collect();
+    let join = |ids: &[i32]| {
+        let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        ids.join(",")
+    };
+    let title = match &options.cpu {
+        Some(cpus) => format!("'CPU(s) {}'", join(&cpus.0)),
+        None if options.all_cpus => "'system wide'".to_string(),
+        None if options.pid.is_empty() => format!("thread id '{}'", join(&options.tid)),
+        None => format!("process id '{}'", join(&options.pid)),
+    };
+    let system_wide = options.system_wide();
+    let mut instant = Instant::now();
+    if options.delay.is_none() {
+        start_counters(&mut counters).unwrap_or_else(|e| counting_failed(e, None, system_wide));
+    }
+    // A command just sets how long to count for.
+    let mut child = None;
+    if !options.command.is_empty() {
+        match Command::new(&options.command[0])
+            .args(&options.command[1..])
+            .spawn()
+        {
+            Ok(c) => child = Some(c),
+            Err(e) => could_not_run(&options.command[0], &e),
+        }
+    }
+    let wake = child.as_ref().and_then(|c| interval::pidfd(c.id() as i32));
+    if let Some(ms) = options.delay {
+        // Let the command, or whatever's counted, get going first.
+        let wake: Vec<RawFd> = wake.iter().map(|f| f.as_raw_fd()).collect();
+        interval::poll_readable(&wake, Duration::from_millis(ms));
+        instant = Instant::now();
+        let pid = child.as_ref().map(|c| c.id() as i32);
+        start_counters(&mut counters).unwrap_or_else(|e| counting_failed(e, pid, system_wide));
+    }
+    // Or --duration does, with a timer to wake us when it's up.
+    let timer = match options.duration {
+        Some(Span(duration)) => match interval::Timer::new(duration) {
+            Ok(timer) => Some(timer),
+            Err(e) => {
+                eprintln!("Error: could not start the --duration timer: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let mut intervals = None;
+    if let Some(ms) = options.interval_print {
+        out.title(&format!(
+            "Performance counter stats for {}, every {} ms:",
+            title, ms
+        ));
+        let period = Duration::from_millis(ms);
+        match Intervals::new(period, options.interval_count, options.no_aggr) {
+            Ok(i) => intervals = Some(i),
+            Err(e) => {
+                eprintln!("Error: could not start the interval timer: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Otherwise a CPU is counted until we're interrupted.
+    let running = || targets.iter().any(|t| t.pid == -1 || alive(t.pid));
+    let timed_out = || matches!(options.duration, Some(Span(d)) if instant.elapsed() >= d);
+    let finished = count_until(
+        out,
+        &mut counters,
+        &mut intervals,
+        wake.as_ref()
+            .map(|f| f.as_raw_fd())
+            .or_else(|| timer.as_ref().map(|t| t.as_raw_fd())),
+        Duration::from_millis(100),
+        |_| match &mut child {
+            Some(child) => {
+                forward_signal(child.id() as i32);
+                !matches!(child.try_wait(), Ok(None))
+            }
+            None => INTERRUPTED.load(Ordering::SeqCst) || timed_out() || !running(),
+        },
     );
+    let finished = match finished {
+        Ok(finished) => finished,
+        Err(e) => counting_failed(e, child.as_ref().map(|c| c.id() as i32), system_wide),
+    };
+    let mut code = 0;
+    if let Some(child) = &mut child {
+        if !finished {
+            // Enough intervals were printed.
+            unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+        }
+        match child.wait() {
+            Ok(status) if finished => code = exit_code(status.into_raw()),
+            _ => {}
+        }
+    }
+    stop_counters(&mut counters).unwrap_or_else(|e| counting_failed(e, None, system_wide));
+    let t = instant.elapsed().as_nanos();
+    if intervals.is_some() {
+        return code;
+    }
 
+    out.title(&format!("Performance counter stats for {}:", title));
+    if options.per_thread {
+        print_counters(out, &counters, t, &Split::Thread(&comms));
+    } else if options.no_aggr {
+        print_counters(out, &counters, t, &Split::Cpu);
+    } else {
+        print_counters(out, &counters, t, &Split::Total);
+    }
+    code
+}
+
+/// Count until `done` says to stop, checking it at least every
+/// `poll`, and as soon as `wake`, if given, becomes readable.
+/// With `-I`, what was counted is printed every interval, and
+/// finally for the part interval when `done`. Returns false if
+/// counting was cut short by `--interval-count` instead, or
+/// the error if the counters couldn't be read.
+fn count_until(
+    out: &mut Output,
+    counters: &mut [Counter],
+    intervals: &mut Option<Intervals>,
+    wake: Option<RawFd>,
+    poll: Duration,
+    mut done: impl FnMut(&mut [Counter]) -> bool,
+) -> Result<bool, SysErr> {
+    loop {
+        if done(counters) {
+            if let Some(intervals) = intervals {
+                intervals.print(out, counters)?;
+            }
+            return Ok(true);
+        }
+        match intervals {
+            Some(intervals) => {
+                if intervals.timer().wait(wake, poll) && intervals.print(out, counters)? {
+                    return Ok(false);
+                }
+            }
+            None => {
+                interval::poll_readable(&wake.into_iter().collect::<Vec<_>>(), poll);
+            }
+        }
+    }
+}
+
+/// How to split up the counts when printing them.
+enum Split<'a> {
+    Total,
+    /// For each CPU counted.
+    Cpu,
+    /// For each thread, named by TID.
+    Thread(&'a BTreeMap<i32, String>),
+}
+
+/// Print the counts of every counter, split up as asked.
+/// `t` is the nanoseconds spent counting.
+fn print_counters(out: &mut Output, counters: &[Counter], t: u128, split: &Split) {
+    let mut rows = Vec::new();
     for counter in counters {
-        if matches!(counter.event.event, StatEvent::TaskClock) {
-            println!(
-                " {:.2} msec task-clock\n CPU utilized: {:.3}",
-                (counter.stop - counter.start) as f64 / 1_000_000.0,
-                (counter.stop - counter.start) as f64 / t as f64
-            );
-        } else {
-            println!(
-                " Number of {}: {}",
-                counter.event.event.to_string(),
-                counter.stop - counter.start
-            );
+        if counter.groups.is_none() {
+            rows.push(Row {
+                events: &counter.events,
+                label: None,
+                reading: None,
+            });
+            continue;
+        }
+        match split {
+            Split::Total => rows.push(Row {
+                events: &counter.events,
+                label: None,
+                reading: Some(counter.reading()),
+            }),
+            Split::Cpu => {
+                for (i, target) in counter.targets.iter().enumerate() {
+                    rows.push(Row {
+                        events: &counter.events,
+                        label: Some(Label::Cpu(target.cpu)),
+                        reading: Some(counter.stop[i].since(&counter.start[i])),
+                    });
+                }
+            }
+            Split::Thread(comms) => {
+                for (tid, reading) in counter.threads() {
+                    let comm = comms.get(&tid).map_or("?", String::as_str);
+                    rows.push(Row {
+                        events: &counter.events,
+                        label: Some(Label::Thread { comm, tid }),
+                        reading: Some(reading),
+                    });
+                }
+            }
+        }
+    }
+    print_rows(out, &rows, t, None);
+}
+
+/// What one event group counted on a CPU or thread, or in total.
+struct Row<'a> {
+    events: &'a StatGroup,
+    label: Option<Label<'a>>,
+    /// `None` if the group can't be counted here.
+    reading: Option<Reading>,
+}
+
+/// Print each row, in the interval starting `interval`
+/// seconds in, if it's for one, with the metrics worked out
+/// from all the rows for the same CPU or thread, and
+/// the topdown tree of each with `--topdown`.
+/// `t` is the nanoseconds spent counting.
+fn print_rows(out: &mut Output, rows: &[Row], t: u128, interval: Option<f64>) {
+    let mut scopes: Vec<(Option<Label>, Counts)> = Vec::new();
+    for row in rows {
+        let reading = match &row.reading {
+            Some(reading) => reading,
+            None => continue,
+        };
+        let i = match scopes.iter().position(|(label, _)| *label == row.label) {
+            Some(i) => i,
+            None => {
+                scopes.push((row.label, Counts::new(t as f64)));
+                scopes.len() - 1
+            }
+        };
+        for (j, spec) in row.events.0.iter().enumerate() {
+            if let Some(count) = reading.scaled(j) {
+                scopes[i].1.add(spec, count as f64);
+            }
+        }
+    }
+    for row in rows {
+        match &row.reading {
+            Some(reading) => {
+                let (_, counts) = scopes
+                    .iter_mut()
+                    .find(|(label, _)| *label == row.label)
+                    .unwrap();
+                print_reading(out, row.events, reading, counts, interval, row.label);
+            }
+            None => print_unsupported(out, row.events, interval),
+        }
+    }
+    for (label, counts) in &scopes {
+        print_topdown(out, counts, interval, *label);
+    }
+}
+
+/// Print the topdown tree worked out from `counts`, with
+/// `--topdown`, if the events it needs were counted.
+fn print_topdown(out: &mut Output, counts: &Counts, interval: Option<f64>, label: Option<Label>) {
+    let shares = match out.topdown().and_then(|topdown| topdown.shares(counts)) {
+        Some(shares) => shares,
+        None => return,
+    };
+    out.shares(&shares, interval, label);
+}
+
+/// Print that the events of a group can't be counted here.
+fn print_unsupported(out: &mut Output, events: &StatGroup, interval: Option<f64>) {
+    for spec in &events.0 {
+        out.count(&Count {
+            event: spec,
+            interval,
+            label: None,
+            value: Value::NotSupported,
+            time_enabled: 0,
+            time_running: 0,
+            runs: None,
+            metric: None,
+        });
+    }
+}
+
+/// Print what one event group counted, in the interval
+/// starting `interval` seconds in, if it's for one,
+/// and on the CPU or thread `label`, if it's split up,
+/// with metrics worked out from `counts`.
+fn print_reading(
+    out: &mut Output,
+    events: &StatGroup,
+    reading: &Reading,
+    counts: &mut Counts,
+    interval: Option<f64>,
+    label: Option<Label>,
+) {
+    for (i, spec) in events.0.iter().enumerate() {
+        let (value, metric) = match reading.scaled(i) {
+            Some(count) => (Value::Counted(count), counts.metric(out.metrics(), spec)),
+            None => (Value::NotCounted, None),
+        };
+        out.count(&Count {
+            event: spec,
+            interval,
+            label,
+            value,
+            time_enabled: reading.time_enabled,
+            time_running: reading.time_running,
+            runs: None,
+            metric,
+        });
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn span_test() {
+    let span = |s: &str| s.parse::<Span>().ok().map(|Span(d)| d);
+    assert_eq!(span("500ms"), Some(Duration::from_millis(500)));
+    assert_eq!(span("1.5s"), Some(Duration::from_millis(1500)));
+    assert_eq!(span("2"), Some(Duration::from_secs(2)));
+    assert_eq!(span("2m"), Some(Duration::from_secs(120)));
+    assert_eq!(span("1h"), Some(Duration::from_secs(3600)));
+    assert_eq!(span("0"), None);
+    assert_eq!(span("0ms"), None);
+    assert_eq!(span("5d"), None);
+    assert_eq!(span("ms"), None);
+    assert_eq!(span("-1s"), None);
+    assert_eq!(span("1e3"), None);
+    // Past about 30 years.
+    assert_eq!(span("300000h"), None);
+    assert!(span("250000h").is_some());
+}
+
+#[test]
+fn exit_code_test() {
+    // Exit codes are in the second byte of a wait status,
+    // and the signal that killed it in the low 7 bits.
+    assert_eq!(exit_code(0), 0);
+    assert_eq!(exit_code(3 << 8), 3);
+    assert_eq!(exit_code(libc::SIGKILL), 128 + libc::SIGKILL);
+    let status = |script: &str| {
+        let status = Command::new("sh").arg("-c").arg(script).status().unwrap();
+        exit_code(status.into_raw())
+    };
+    assert_eq!(status("exit 3"), 3);
+    assert_eq!(status("kill -TERM $$"), 128 + libc::SIGTERM);
+}
+
+#[test]
+fn threads_test() {
+    let pid = std::process::id() as i32;
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+    assert!(alive(tid));
+    // Beyond the largest PID the kernel allows.
+    assert!(!alive(i32::MAX));
+    let tasks = threads(&[pid], &[]).unwrap();
+    assert_eq!(tasks[0], pid);
+    assert!(tasks.contains(&tid));
+    assert_eq!(threads(&[], &[tid]), Ok(vec![tid]));
+    assert_eq!(
+        threads(&[i32::MAX], &[]),
+        Err(format!("no process with PID {}", i32::MAX))
+    );
+    assert_eq!(
+        threads(&[], &[i32::MAX]),
+        Err(format!("no thread with TID {}", i32::MAX))
+    );
+
+    // A child that has exited but not been reaped is a zombie.
+    let mut child = Command::new("true").spawn().unwrap();
+    let child_pid = child.id() as i32;
+    for _ in 0..500 {
+        if !alive(child_pid) {
+            break;
         }
+        std::thread::sleep(Duration::from_millis(10));
     }
+    assert!(!alive(child_pid));
+    assert!(Path::new(&format!("/proc/{}", child_pid)).exists());
+    child.wait().unwrap();
 }
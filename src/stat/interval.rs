@@ -0,0 +1,197 @@
+//! Printing counts every interval while counting, for `ruperf stat -I`.
+
+use super::output::{Label, Output};
+use super::{print_rows, Counter, Row};
+use crate::event::open::{Reading, SysErr};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+/// A timer that fires every period. It's a timerfd,
+/// so intervals don't drift by how long printing takes.
+pub struct Timer {
+    fd: RawFd,
+}
+
+impl Timer {
+    /// Start a timer that first fires one `period` from now.
+    pub fn new(period: Duration) -> io::Result<Self> {
+        let fd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let timer = Timer { fd };
+        let period = libc::timespec {
+            tv_sec: period.as_secs() as libc::time_t,
+            tv_nsec: period.subsec_nanos() as libc::c_long,
+        };
+        let spec = libc::itimerspec {
+            it_interval: period,
+            it_value: period,
+        };
+        if unsafe { libc::timerfd_settime(fd, 0, &spec, std::ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(timer)
+    }
+    /// Wait up to `timeout` for the timer to fire, or for `wake`, if
+    /// given, to become readable. Returns whether the timer fired.
+    pub fn wait(&self, wake: Option<RawFd>, timeout: Duration) -> bool {
+        let mut fds = vec![self.fd];
+        fds.extend(wake);
+        if !poll_readable(&fds, timeout)[0] {
+            return false;
+        }
+        // How many times it fired; if printing fell behind,
+        // the missed intervals are folded into this one.
+        let mut fired = 0u64;
+        unsafe { libc::read(self.fd, &mut fired as *mut u64 as *mut libc::c_void, 8) };
+        true
+    }
+}
+
+impl AsRawFd for Timer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Wait up to `timeout` for any of `fds` to become readable,
+/// or just sleep if there are none. Returns which are readable.
+pub fn poll_readable(fds: &[RawFd], timeout: Duration) -> Vec<bool> {
+    let mut polled: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let ret = unsafe {
+        libc::poll(
+            polled.as_mut_ptr(),
+            polled.len() as libc::nfds_t,
+            timeout.as_millis() as libc::c_int,
+        )
+    };
+    polled
+        .iter()
+        .map(|p| ret > 0 && p.revents & libc::POLLIN != 0)
+        .collect()
+}
+
+/// A pidfd for the process `pid`, which becomes readable when it
+/// exits, or `None` on kernels older than 5.3, which lack them.
+pub fn pidfd(pid: i32) -> Option<File> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        return None;
+    }
+    Some(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+/// Prints what every counter counted each interval,
+/// timestamped with the seconds since counting started.
+pub struct Intervals {
+    timer: Timer,
+    start: Instant,
+    last: Instant,
+    printed: usize,
+    /// Stop after this many intervals.
+    limit: Option<usize>,
+    per_cpu: bool,
+}
+
+impl Intervals {
+    /// Start timing intervals of `period`, from now.
+    pub fn new(period: Duration, limit: Option<usize>, per_cpu: bool) -> io::Result<Self> {
+        let now = Instant::now();
+        Ok(Intervals {
+            timer: Timer::new(period)?,
+            start: now,
+            last: now,
+            printed: 0,
+            limit,
+            per_cpu,
+        })
+    }
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+    /// Print what was counted since the last interval.
+    /// Returns true once `limit` intervals have been printed,
+    /// or the error if the counters couldn't be read.
+    pub fn print(&mut self, out: &mut Output, counters: &mut [Counter]) -> Result<bool, SysErr> {
+        let now = Instant::now();
+        let t = now.duration_since(self.last).as_nanos();
+        let stamp = Some(now.duration_since(self.start).as_secs_f64());
+        self.last = now;
+        let mut rows = Vec::new();
+        for counter in counters.iter_mut() {
+            if counter.groups.is_none() {
+                rows.push(Row {
+                    events: &counter.events,
+                    label: None,
+                    reading: None,
+                });
+                continue;
+            }
+            let deltas = counter.interval()?;
+            if self.per_cpu {
+                for (target, reading) in counter.targets.iter().zip(deltas) {
+                    rows.push(Row {
+                        events: &counter.events,
+                        label: Some(Label::Cpu(target.cpu)),
+                        reading: Some(reading),
+                    });
+                }
+            } else {
+                let mut total = Reading::default();
+                for reading in &deltas {
+                    total.add(reading);
+                }
+                rows.push(Row {
+                    events: &counter.events,
+                    label: None,
+                    reading: Some(total),
+                });
+            }
+        }
+        print_rows(out, &rows, t, stamp);
+        self.printed += 1;
+        Ok(matches!(self.limit, Some(limit) if self.printed >= limit))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn timer_test() {
+    let timer = Timer::new(Duration::from_millis(20)).unwrap();
+    let start = Instant::now();
+    assert!(!timer.wait(None, Duration::from_millis(1)));
+    assert!(timer.wait(None, Duration::from_secs(5)));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    // It keeps firing, every period.
+    assert!(timer.wait(None, Duration::from_secs(5)));
+    assert!(start.elapsed() >= Duration::from_millis(40));
+}
+
+#[test]
+fn poll_readable_test() {
+    let start = Instant::now();
+    assert!(poll_readable(&[], Duration::from_millis(20)).is_empty());
+    assert!(start.elapsed() >= Duration::from_millis(20));
+
+    let (reader, mut writer) = os_pipe::pipe().unwrap();
+    let fds = [reader.as_raw_fd()];
+    assert_eq!(poll_readable(&fds, Duration::from_millis(1)), vec![false]);
+    io::Write::write_all(&mut writer, b"x").unwrap();
+    assert_eq!(poll_readable(&fds, Duration::from_secs(5)), vec![true]);
+}
@@ -0,0 +1,649 @@
+//! Metrics derived from counts, like perf's shadow stats:
+//! instructions per cycle next to `instructions`,
+//! the branch miss rate next to `branch-misses`, and so on,
+//! and the metrics of the CPU's pmu-events tables, for `-M`.
+//!
+//! A metric is an arithmetic expression over event names,
+//! e.g. `instructions / cycles`, with `+ - * /`, `<` and `>`,
+//! `a if cond else b`, `min`, `max` and `d_ratio` calls,
+//! parentheses and numbers. A `-` between two names needs spaces
+//! around it, since event names like `branch-misses` contain dashes.
+//! `duration_time` is the nanoseconds spent counting, and
+//! `#SMT_on` and `#num_cpus_online` describe the machine.
+//! New built in metrics are added to `METRICS`.
+
+use crate::event::open::{lookup, online_cpus, EventSpec, PmuEvents, SyntaxErr, VendorMetric};
+use std::fs;
+use std::str::FromStr;
+
+/// A built in metric, printed next to the count of an event.
+pub struct Builtin {
+    /// The event it's printed next to.
+    pub event: &'static str,
+    pub expr: &'static str,
+    /// What it measures, printed after it.
+    pub unit: &'static str,
+}
+
+/// The metrics shown next to counts. Only the first one for
+/// an event whose inputs were all counted is shown.
+pub const METRICS: &[Builtin] = &[
+    Builtin {
+        event: "task-clock",
+        expr: "task-clock / duration_time",
+        unit: "CPU utilized",
+    },
+    Builtin {
+        event: "cycles",
+        expr: "cycles / task-clock",
+        unit: "GHz",
+    },
+    Builtin {
+        event: "instructions",
+        expr: "instructions / cycles",
+        unit: "insn per cycle",
+    },
+    Builtin {
+        event: "branch-misses",
+        expr: "100 * branch-misses / branches",
+        unit: "% of all branches",
+    },
+    Builtin {
+        event: "L1-dcache-load-misses",
+        expr: "100 * L1-dcache-load-misses / L1-dcache-loads",
+        unit: "% of all L1-dcache accesses",
+    },
+    Builtin {
+        event: "LLC-load-misses",
+        expr: "100 * LLC-load-misses / LLC-loads",
+        unit: "% of all LLC accesses",
+    },
+    Builtin {
+        event: "context-switches",
+        expr: "1e6 * context-switches / task-clock",
+        unit: "K/sec",
+    },
+];
+
+/// How many metrics deep one metric may refer to others.
+const MAX_DEPTH: usize = 16;
+
+/// A metric, printed next to the count of an event.
+#[derive(Debug, Clone)]
+pub struct Metric {
+    pub name: String,
+    /// The event it's printed next to.
+    pub event: String,
+    /// With any other metrics it refers to filled in.
+    pub expr: Expr,
+    /// What it's multiplied by, e.g. 100 for a percentage.
+    pub scale: f64,
+    /// What it measures, printed after it.
+    pub unit: String,
+}
+
+impl Metric {
+    /// A metric from the tables, printed next
+    /// to the first event its expression names.
+    pub fn vendor(metric: &VendorMetric, tables: &PmuEvents) -> Result<Self, SyntaxErr> {
+        let expr = inline(metric.expr.parse()?, tables, 0)?;
+        let event = match expr.events().first() {
+            Some(event) => event.to_string(),
+            None => {
+                return Err(SyntaxErr {
+                    column: 1,
+                    msg: "no events to count".to_string(),
+                })
+            }
+        };
+        // E.g. `100%`, or `1per_instr`.
+        let scale_unit = metric.scale_unit.as_deref().unwrap_or("1");
+        let split = scale_unit
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == 'e'))
+            .unwrap_or(scale_unit.len());
+        let (scale, unit) = scale_unit.split_at(split);
+        Ok(Metric {
+            name: metric.name.clone(),
+            event,
+            expr,
+            scale: scale.parse().unwrap_or(1.0),
+            unit: format!("{} {}", unit, metric.name).trim().to_string(),
+        })
+    }
+    /// The events the metric needs counted.
+    pub fn events(&self) -> Vec<&str> {
+        self.expr.events()
+    }
+}
+
+/// The built in metrics, from `METRICS`.
+pub fn builtin() -> Vec<Metric> {
+    METRICS
+        .iter()
+        .map(|metric| Metric {
+            name: metric.unit.to_string(),
+            event: metric.event.to_string(),
+            expr: metric.expr.parse().unwrap(),
+            scale: 1.0,
+            unit: metric.unit.to_string(),
+        })
+        .collect()
+}
+
+/// Fill in the other metrics `expr` refers to by name.
+fn inline(expr: Expr, tables: &PmuEvents, depth: usize) -> Result<Expr, SyntaxErr> {
+    let inline_box =
+        |e: Box<Expr>| -> Result<Box<Expr>, SyntaxErr> { Ok(Box::new(inline(*e, tables, depth)?)) };
+    Ok(match expr {
+        Expr::Event(name) => match tables.metrics.iter().find(|m| m.name == name) {
+            Some(_) if depth == MAX_DEPTH => {
+                return Err(SyntaxErr {
+                    column: 1,
+                    msg: format!("metric '{}' refers to itself", name),
+                })
+            }
+            Some(metric) => inline(metric.expr.parse()?, tables, depth + 1)?,
+            None => Expr::Event(name),
+        },
+        Expr::Neg(e) => Expr::Neg(inline_box(e)?),
+        Expr::Binary(l, op, r) => Expr::Binary(inline_box(l)?, op, inline_box(r)?),
+        Expr::If(value, cond, otherwise) => Expr::If(
+            inline_box(value)?,
+            inline_box(cond)?,
+            inline_box(otherwise)?,
+        ),
+        Expr::Call(f, args) => Expr::Call(
+            f,
+            args.into_iter()
+                .map(|a| inline(a, tables, depth))
+                .collect::<Result<_, _>>()?,
+        ),
+        Expr::Number(n) => Expr::Number(n),
+    })
+}
+
+/// A parsed metric expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Event(String),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, char, Box<Expr>),
+    /// `value if cond else otherwise`.
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+/// The functions metrics may call, and how many arguments they take.
+const FUNCTIONS: &[(&str, usize)] = &[("min", 2), ("max", 2), ("d_ratio", 2)];
+
+impl FromStr for Expr {
+    type Err = SyntaxErr;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { input: s, pos: 0 };
+        let expr = parser.cond()?;
+        match parser.peek() {
+            Some(c) => Err(parser.err(format!("unexpected '{}'", c))),
+            None => Ok(expr),
+        }
+    }
+}
+
+impl Expr {
+    /// Work the expression out, looking each event's count up with
+    /// `count`. `None` if an event wasn't counted or it divides by 0.
+    pub fn eval(&self, count: &dyn Fn(&str) -> Option<f64>) -> Option<f64> {
+        match self {
+            Expr::Number(n) => Some(*n),
+            Expr::Event(name) => count(name),
+            Expr::Neg(e) => Some(-e.eval(count)?),
+            Expr::Binary(l, op, r) => {
+                let (l, r) = (l.eval(count)?, r.eval(count)?);
+                match op {
+                    '+' => Some(l + r),
+                    '-' => Some(l - r),
+                    '*' => Some(l * r),
+                    '<' => Some((l < r) as u8 as f64),
+                    '>' => Some((l > r) as u8 as f64),
+                    _ if r == 0.0 => None,
+                    _ => Some(l / r),
+                }
+            }
+            Expr::If(value, cond, otherwise) => {
+                if cond.eval(count)? != 0.0 {
+                    value.eval(count)
+                } else {
+                    otherwise.eval(count)
+                }
+            }
+            Expr::Call(f, args) => {
+                let (a, b) = (args[0].eval(count)?, args[1].eval(count)?);
+                match f.as_str() {
+                    "min" => Some(a.min(b)),
+                    "max" => Some(a.max(b)),
+                    // Like perf, a ratio of nothing is 0.
+                    _ if b == 0.0 => Some(0.0),
+                    _ => Some(a / b),
+                }
+            }
+        }
+    }
+    /// The events named, in order, leaving out
+    /// `duration_time` and `#` literals, which aren't counted.
+    pub fn events(&self) -> Vec<&str> {
+        let mut events = Vec::new();
+        self.collect_events(&mut events);
+        events
+    }
+    fn collect_events<'a>(&'a self, events: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Event(name) => {
+                let counted = !name.starts_with('#') && name != "duration_time";
+                if counted && !events.contains(&name.as_str()) {
+                    events.push(name);
+                }
+            }
+            Expr::Neg(e) => e.collect_events(events),
+            Expr::Binary(l, _, r) => {
+                l.collect_events(events);
+                r.collect_events(events);
+            }
+            Expr::If(value, cond, otherwise) => {
+                value.collect_events(events);
+                cond.collect_events(events);
+                otherwise.collect_events(events);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|a| a.collect_events(events)),
+        }
+    }
+}
+
+/// A recursive descent parser over `input`:
+///
+/// ```text
+/// cond    := compare ['if' compare 'else' cond]
+/// compare := sum [('<' | '>') sum]
+/// sum     := product (('+' | '-') product)*
+/// product := factor (('*' | '/') factor)*
+/// factor  := number | name | name '(' cond (',' cond)* ')'
+///          | '(' cond ')' | '-' factor
+/// ```
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// The next character that isn't whitespace.
+    fn peek(&mut self) -> Option<char> {
+        let rest = &self.input[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+        self.input[self.pos..].chars().next()
+    }
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            return true;
+        }
+        false
+    }
+    /// Eat the keyword `word`, if it's next.
+    fn keyword(&mut self, word: &str) -> bool {
+        self.peek();
+        let start = self.pos;
+        if self.name() == word {
+            return true;
+        }
+        self.pos = start;
+        false
+    }
+    fn err(&self, msg: String) -> SyntaxErr {
+        SyntaxErr {
+            column: self.input[..self.pos].chars().count() + 1,
+            msg,
+        }
+    }
+    fn cond(&mut self) -> Result<Expr, SyntaxErr> {
+        let value = self.compare()?;
+        if !self.keyword("if") {
+            return Ok(value);
+        }
+        let cond = self.compare()?;
+        if !self.keyword("else") {
+            return Err(self.err("expected 'else'".to_string()));
+        }
+        let otherwise = self.cond()?;
+        Ok(Expr::If(
+            Box::new(value),
+            Box::new(cond),
+            Box::new(otherwise),
+        ))
+    }
+    fn compare(&mut self) -> Result<Expr, SyntaxErr> {
+        let expr = self.sum()?;
+        match self.peek() {
+            Some(op @ '<') | Some(op @ '>') => {
+                self.pos += 1;
+                Ok(Expr::Binary(Box::new(expr), op, Box::new(self.sum()?)))
+            }
+            _ => Ok(expr),
+        }
+    }
+    fn sum(&mut self) -> Result<Expr, SyntaxErr> {
+        let mut expr = self.product()?;
+        loop {
+            let op = match self.peek() {
+                Some(c @ '+') | Some(c @ '-') => c,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.product()?));
+        }
+    }
+    fn product(&mut self) -> Result<Expr, SyntaxErr> {
+        let mut expr = self.factor()?;
+        loop {
+            let op = match self.peek() {
+                Some(c @ '*') | Some(c @ '/') => c,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.factor()?));
+        }
+    }
+    fn factor(&mut self) -> Result<Expr, SyntaxErr> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.cond()?;
+                if !self.eat(')') {
+                    return Err(self.err("expected ')'".to_string()));
+                }
+                Ok(expr)
+            }
+            Some('-') => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.factor()?)))
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_alphabetic() || c == '_' || c == '#' => {
+                let start = self.pos;
+                let name = self.name();
+                if !self.eat('(') {
+                    return Ok(Expr::Event(name));
+                }
+                self.call(name, start)
+            }
+            Some(c) => Err(self.err(format!("unexpected '{}'", c))),
+            None => Err(self.err("unexpected end".to_string())),
+        }
+    }
+    /// The arguments of a call to `f`, found at `start`,
+    /// from just after the `(`.
+    fn call(&mut self, f: String, start: usize) -> Result<Expr, SyntaxErr> {
+        let mut args = vec![self.cond()?];
+        while self.eat(',') {
+            args.push(self.cond()?);
+        }
+        if !self.eat(')') {
+            return Err(self.err("expected ')'".to_string()));
+        }
+        match FUNCTIONS.iter().find(|(name, _)| *name == f) {
+            Some((_, n)) if *n == args.len() => Ok(Expr::Call(f, args)),
+            Some((_, n)) => {
+                self.pos = start;
+                Err(self.err(format!("{}() takes {} arguments", f, n)))
+            }
+            None => {
+                self.pos = start;
+                Err(self.err(format!("unknown function '{}'", f)))
+            }
+        }
+    }
+    /// A number like `100`, `0.5` or `1e9`.
+    fn number(&mut self) -> Result<Expr, SyntaxErr> {
+        let start = self.pos;
+        let bytes = self.input.as_bytes();
+        while self.pos < bytes.len() {
+            let c = bytes[self.pos];
+            let exponent_sign =
+                (c == b'+' || c == b'-') && matches!(bytes[self.pos - 1], b'e' | b'E');
+            if !(c.is_ascii_digit() || c == b'.' || c == b'e' || c == b'E' || exponent_sign) {
+                break;
+            }
+            self.pos += 1;
+        }
+        match self.input[start..self.pos].parse() {
+            Ok(n) => Ok(Expr::Number(n)),
+            Err(_) => {
+                self.pos = start;
+                Err(self.err("invalid number".to_string()))
+            }
+        }
+    }
+    /// An event name, which may have dashes and dots
+    /// inside it, e.g. `L1-dcache-loads` or `inst_retired.any`,
+    /// or a `#` literal.
+    fn name(&mut self) -> String {
+        let start = self.pos;
+        let chars: Vec<char> = self.input[start..].chars().collect();
+        let is_name = |c: char| c.is_alphanumeric() || c == '_' || c == '.';
+        let mut len = 0;
+        while len < chars.len() {
+            let hash = len == 0 && chars[0] == '#';
+            let inner_dash =
+                chars[len] == '-' && matches!(chars.get(len + 1), Some(c) if is_name(*c));
+            if !(is_name(chars[len]) || inner_dash || hash) {
+                break;
+            }
+            len += 1;
+        }
+        let name: String = chars[..len].iter().collect();
+        self.pos += name.len();
+        name
+    }
+}
+
+/// The value of a `#` literal, which describes the machine.
+fn literal(name: &str) -> Option<f64> {
+    match name.to_ascii_lowercase().as_str() {
+        "#smt_on" => {
+            let active = fs::read_to_string("/sys/devices/system/cpu/smt/active").ok()?;
+            active.trim().parse().ok()
+        }
+        "#num_cpus_online" => Some(online_cpus().ok()?.len() as f64),
+        _ => None,
+    }
+}
+
+/// Everything counted over the same time, on the same CPU or
+/// thread or in total, which metrics are worked out from.
+pub struct Counts {
+    counts: Vec<(EventSpec, f64)>,
+    /// `duration_time`, in nanoseconds.
+    duration: f64,
+    /// The metrics already shown, so each is shown once.
+    shown: Vec<usize>,
+}
+
+impl Counts {
+    pub fn new(duration: f64) -> Self {
+        Counts {
+            counts: Vec::new(),
+            duration,
+            shown: Vec::new(),
+        }
+    }
+    pub fn add(&mut self, spec: &EventSpec, count: f64) {
+        self.counts.push((spec.clone(), count));
+    }
+    /// The count of the event `name`.
+    pub fn get(&self, name: &str) -> Option<f64> {
+        if name == "duration_time" {
+            return Some(self.duration);
+        }
+        if name.starts_with('#') {
+            return literal(name);
+        }
+        let found = self.counts.iter().find(|(spec, _)| is_event(spec, name));
+        found.map(|(_, count)| *count)
+    }
+    /// The first of `metrics` for `spec` that hasn't been shown
+    /// yet and can be worked out, and its unit.
+    pub fn metric(&mut self, metrics: &[Metric], spec: &EventSpec) -> Option<(f64, String)> {
+        let (i, value) = metrics.iter().enumerate().find_map(|(i, metric)| {
+            if self.shown.contains(&i) || !is_event(spec, &metric.event) {
+                return None;
+            }
+            Some((i, metric.expr.eval(&|name| self.get(name))? * metric.scale))
+        })?;
+        self.shown.push(i);
+        Some((value, metrics[i].unit.clone()))
+    }
+}
+
+/// Whether `spec` is the event `name`, or one it's an alias of,
+/// e.g. `cycles` matches `cpu-cycles:u`, whatever it counts in.
+fn is_event(spec: &EventSpec, name: &str) -> bool {
+    match lookup(name) {
+        Some(event) => spec.type_ == event.type_ && spec.config == event.config,
+        None => {
+            let bare = spec.name.split(':').next().unwrap_or_default();
+            bare.eq_ignore_ascii_case(name)
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn expr_test() {
+    let expr: Expr = "100 * (a - b.c) / L1-dcache-loads + -1e3".parse().unwrap();
+    let count = |name: &str| match name {
+        "a" => Some(7.0),
+        "b.c" => Some(3.0),
+        "L1-dcache-loads" => Some(8.0),
+        _ => None,
+    };
+    assert_eq!(expr.eval(&count), Some(100.0 * 4.0 / 8.0 - 1000.0));
+    let zero: Expr = "a / (b.c - 3)".parse().unwrap();
+    assert_eq!(zero.eval(&count), None);
+    let missing: Expr = "a / other".parse().unwrap();
+    assert_eq!(missing.eval(&count), None);
+    let cond: Expr = "(a / 2 if b.c > 2 else a) + max(a, 9) + d_ratio(a, 0)"
+        .parse()
+        .unwrap();
+    assert_eq!(cond.eval(&count), Some(3.5 + 9.0));
+    assert_eq!(cond.events(), vec!["a", "b.c"]);
+
+    let err = "a * (b".parse::<Expr>().unwrap_err();
+    assert_eq!(err.column, 7);
+    assert!("a $ b".parse::<Expr>().is_err());
+    assert_eq!("a if b".parse::<Expr>().unwrap_err().msg, "expected 'else'");
+    let err = "1 + foo(a, b)".parse::<Expr>().unwrap_err();
+    assert_eq!(
+        (err.column, err.msg.as_str()),
+        (5, "unknown function 'foo'")
+    );
+    for metric in METRICS {
+        assert!(metric.expr.parse::<Expr>().is_ok(), "{}", metric.expr);
+        assert!(lookup(metric.event).is_some(), "{}", metric.event);
+    }
+}
+
+#[test]
+fn metrics_test() {
+    let spec = |name: &str| lookup(name).unwrap();
+    let metrics = builtin();
+    let mut counts = Counts::new(2e9);
+    counts.add(&spec("cpu-cycles").name("cycles:u"), 3e9);
+    counts.add(&spec("instructions"), 6e9);
+    counts.add(&spec("task-clock"), 1e9);
+    counts.add(&spec("branch-misses"), 5.0);
+    let metric = |counts: &mut Counts, name| counts.metric(&metrics, &spec(name));
+    assert_eq!(
+        metric(&mut counts, "instructions"),
+        Some((2.0, "insn per cycle".to_string()))
+    );
+    // Each metric is shown once.
+    assert_eq!(metric(&mut counts, "instructions"), None);
+    assert_eq!(
+        metric(&mut counts, "cycles"),
+        Some((3.0, "GHz".to_string()))
+    );
+    assert_eq!(
+        metric(&mut counts, "task-clock"),
+        Some((0.5, "CPU utilized".to_string()))
+    );
+    // No branches were counted to work out the miss rate from.
+    assert_eq!(metric(&mut counts, "branch-misses"), None);
+    counts.add(&spec("branches"), 200.0);
+    assert_eq!(
+        metric(&mut counts, "branch-misses"),
+        Some((2.5, "% of all branches".to_string()))
+    );
+}
+
+#[test]
+fn vendor_metrics_test() {
+    use crate::event::open::fixture;
+    let tables = PmuEvents::load(&fixture("pmu-events/x86"), "GenuineIntel-6-9A-3").unwrap();
+    let metrics: Vec<Metric> = tables
+        .metrics
+        .iter()
+        .map(|m| Metric::vendor(m, &tables).unwrap())
+        .collect();
+    let (ipc, cpi, branch) = (&metrics[0], &metrics[1], &metrics[2]);
+    assert_eq!(ipc.event, "INST_RETIRED.ANY");
+    assert_eq!(
+        ipc.events(),
+        vec!["INST_RETIRED.ANY", "CPU_CLK_UNHALTED.THREAD"]
+    );
+    // CPI is `1 / IPC`, so it needs IPC's events.
+    assert_eq!(cpi.event, "INST_RETIRED.ANY");
+    assert_eq!(cpi.events(), ipc.events());
+    assert_eq!(
+        (branch.scale, branch.unit.as_str()),
+        (100.0, "% Branch_Misprediction_Ratio")
+    );
+    assert_eq!(
+        metrics[3].events(),
+        vec!["UOPS_ISSUED.ANY_STALLS", "CPU_CLK_UNHALTED.THREAD"]
+    );
+
+    let mut counts = Counts::new(1e9);
+    for (name, count) in &[
+        ("inst_retired.any", 300.0),
+        ("CPU_CLK_UNHALTED.THREAD", 200.0),
+        ("BR_MISP_RETIRED.ALL_BRANCHES", 1.0),
+        ("BR_INST_RETIRED.ALL_BRANCHES", 40.0),
+    ] {
+        counts.add(&tables.event(name).unwrap(), *count);
+    }
+    let inst = tables.event("INST_RETIRED.ANY").unwrap();
+    assert_eq!(
+        counts.metric(&metrics, &inst),
+        Some((1.5, "IPC".to_string()))
+    );
+    let (value, unit) = counts.metric(&metrics, &inst).unwrap();
+    assert!((value - 2.0 / 3.0).abs() < 1e-9);
+    assert_eq!(unit, "CPI");
+    let misses = tables.event("BR_MISP_RETIRED.ALL_BRANCHES").unwrap();
+    assert_eq!(
+        counts.metric(&metrics, &misses),
+        Some((2.5, "% Branch_Misprediction_Ratio".to_string()))
+    );
+
+    let looping = VendorMetric {
+        name: "Loop".to_string(),
+        expr: "Loop + 1".to_string(),
+        groups: Vec::new(),
+        scale_unit: None,
+        description: String::new(),
+    };
+    let tables = PmuEvents {
+        events: Vec::new(),
+        metrics: vec![looping.clone()],
+    };
+    assert!(Metric::vendor(&looping, &tables).is_err());
+}
@@ -0,0 +1,536 @@
+//! Where and how `ruperf stat` prints counts: as text, as `-x`
+//! separated values laid out like `perf stat -x`, or as JSON,
+//! one object per line, to stdout or the `-o` file.
+//! The JSON schema is described in `docs/stat-json.md`.
+
+use super::metric::Metric;
+use super::stats::Stats;
+use super::topdown::Topdown;
+use crate::event::open::EventSpec;
+use crate::stat::StatEvent;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+
+/// The version of the JSON schema, to be bumped whenever
+/// a field changes meaning or is removed.
+pub const JSON_VERSION: u32 = 1;
+
+/// How counts are printed.
+pub enum Format {
+    Text,
+    /// Fields separated by the given string.
+    Csv(String),
+    Json,
+}
+
+/// What a count is split by, if it isn't a total.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Label<'a> {
+    Cpu(i32),
+    Thread { comm: &'a str, tid: i32 },
+}
+
+/// An event's count, or why there isn't one.
+pub enum Value {
+    Counted(u64),
+    NotCounted,
+    NotSupported,
+}
+
+/// One event's count, as printed.
+pub struct Count<'a> {
+    pub event: &'a EventSpec,
+    /// Seconds since counting started, for an `-I` interval.
+    pub interval: Option<f64>,
+    pub label: Option<Label<'a>>,
+    /// With `-r`, the mean of `runs`.
+    pub value: Value,
+    pub time_enabled: u64,
+    pub time_running: u64,
+    /// The count from each run, with `-r`.
+    pub runs: Option<&'a Stats>,
+    /// A metric derived from the count, and its unit.
+    pub metric: Option<(f64, String)>,
+}
+
+impl Count<'_> {
+    /// The unit the count is printed in, and what to divide it by for it.
+    fn unit(&self) -> (&'static str, f64) {
+        if self.event.is_task_clock() {
+            ("msec", 1e6)
+        } else {
+            ("", 1.0)
+        }
+    }
+    /// The count, or the mean count, in its unit.
+    fn value(&self) -> Option<f64> {
+        let (_, scale) = self.unit();
+        match (&self.value, self.runs) {
+            (Value::Counted(_), Some(runs)) => Some(runs.mean() / scale),
+            (Value::Counted(count), None) => Some(*count as f64 / scale),
+            _ => None,
+        }
+    }
+    /// The percentage of the time enabled the counter
+    /// was running, less than 100 if it was multiplexed.
+    fn running(&self) -> f64 {
+        if self.time_enabled == 0 {
+            return 100.0;
+        }
+        100.0 * self.time_running as f64 / self.time_enabled as f64
+    }
+}
+
+/// A topdown area's share of the CPU's pipeline slots, as printed.
+pub struct Share {
+    /// E.g. `tma_retiring`.
+    pub name: &'static str,
+    pub description: &'static str,
+    /// How deep in the tree it is, 1 or 2.
+    pub level: usize,
+    pub percent: f64,
+}
+
+/// A count as a JSON object. See `docs/stat-json.md`.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct JsonCount<'a> {
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tid: Option<i32>,
+    event: String,
+    counter_value: Option<f64>,
+    supported: bool,
+    unit: &'static str,
+    time_enabled: u64,
+    time_running: u64,
+    pcnt_running: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variance: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rel_stddev: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    runs: Option<Vec<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metric_value: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metric_unit: Option<&'a str>,
+}
+
+/// A topdown area's share as a JSON object. See `docs/stat-json.md`.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct JsonShare<'a> {
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tid: Option<i32>,
+    topdown: &'static str,
+    level: usize,
+    percent: f64,
+}
+
+/// Prints counts in the chosen format.
+pub struct Output {
+    out: Box<dyn Write>,
+    format: Format,
+    /// The metrics to work out from the counts and show with them.
+    metrics: Vec<Metric>,
+    /// The topdown areas to show after the counts, with `--topdown`.
+    topdown: Option<Topdown>,
+}
+
+impl Output {
+    /// Print to `path`, or stdout if `None`, appending
+    /// to the file rather than replacing it if `append`.
+    pub fn new(format: Format, path: Option<&str>, append: bool) -> io::Result<Self> {
+        let out: Box<dyn Write> = match path {
+            Some(path) => Box::new(
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(append)
+                    .truncate(!append)
+                    .open(path)?,
+            ),
+            None => Box::new(io::stdout()),
+        };
+        Ok(Output {
+            out,
+            format,
+            metrics: Vec::new(),
+            topdown: None,
+        })
+    }
+    /// Show `metrics` next to the counts they're worked out from.
+    pub fn with_metrics(mut self, metrics: Vec<Metric>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+    pub fn metrics(&self) -> &[Metric] {
+        &self.metrics
+    }
+    /// Show how the slots were spent, worked out by `topdown`.
+    pub fn with_topdown(mut self, topdown: Option<Topdown>) -> Self {
+        self.topdown = topdown;
+        self
+    }
+    pub fn topdown(&self) -> Option<&Topdown> {
+        self.topdown.as_ref()
+    }
+    /// Print what's being counted, like `Performance counter
+    /// stats for 'ls':`. Only text output has one.
+    pub fn title(&mut self, title: &str) {
+        if let Format::Text = self.format {
+            self.line(&format!("{}\n", title));
+        }
+    }
+    /// Print the mean time runs took, with `-r`. Only text output has it.
+    pub fn elapsed(&mut self, elapsed: &Stats) {
+        if let Format::Text = self.format {
+            self.line(&format!(
+                "\n {:.6} seconds time elapsed  ( +- {:.2}% )",
+                elapsed.mean() / 1e9,
+                elapsed.rel_stddev()
+            ));
+        }
+    }
+    pub fn count(&mut self, count: &Count) {
+        let text = match &self.format {
+            Format::Text => text(count),
+            Format::Csv(sep) => csv(count, sep),
+            Format::Json => json(count),
+        };
+        self.line(&text);
+    }
+    /// Print the topdown tree, for the interval starting
+    /// `interval` seconds in, and the CPU or thread `label`,
+    /// if it's for one. Text indents each level under the last.
+    pub fn shares(&mut self, shares: &[Share], interval: Option<f64>, label: Option<Label>) {
+        let lines: Vec<String> = match &self.format {
+            Format::Text => text_shares(shares, interval, &label),
+            Format::Csv(sep) => shares
+                .iter()
+                .map(|share| csv_share(share, interval, &label, sep))
+                .collect(),
+            Format::Json => shares
+                .iter()
+                .map(|share| json_share(share, interval, &label))
+                .collect(),
+        };
+        self.line(&lines.join("\n"));
+    }
+    fn line(&mut self, line: &str) {
+        // Like println!, give up if the reader has gone away.
+        if let Err(e) = writeln!(self.out, "{}", line) {
+            eprintln!("Error: could not write the counts: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The interval timestamp and label a line starts with.
+fn prefix(interval: Option<f64>, label: &Option<Label>) -> String {
+    let mut prefix = String::new();
+    if let Some(interval) = interval {
+        prefix += &format!("{:>14.9} ", interval);
+    }
+    match label {
+        Some(Label::Cpu(cpu)) => prefix += &format!("CPU{} ", cpu),
+        // Like perf, `comm-tid`.
+        Some(Label::Thread { comm, tid }) => prefix += &format!("{}-{} ", comm, tid),
+        None => {}
+    }
+    prefix
+}
+
+/// How text output names an event: the presets in
+/// prose, e.g. `task clock`, anything else as it was given.
+fn text_name(event: &EventSpec) -> String {
+    match StatEvent::ALL
+        .iter()
+        .find(|preset| preset.name() == event.name)
+    {
+        Some(preset) => preset.to_string(),
+        None => event.name.clone(),
+    }
+}
+
+fn text(count: &Count) -> String {
+    let prefix = prefix(count.interval, &count.label);
+    let event = text_name(count.event);
+    let value = match count.value() {
+        Some(value) => value,
+        None if matches!(count.value, Value::NotSupported) => {
+            return format!(" {}<not supported> {}", prefix, event)
+        }
+        None => return format!(" {}<not counted> {}", prefix, event),
+    };
+    let suffix = match count.runs {
+        Some(runs) => {
+            let (_, scale) = count.unit();
+            let (min, max) = (runs.min().unwrap_or(0), runs.max().unwrap_or(0));
+            let range = if scale == 1.0 {
+                format!("{}, max {}", min, max)
+            } else {
+                format!("{:.2}, max {:.2}", min as f64 / scale, max as f64 / scale)
+            };
+            format!("  ( +- {:.2}% )  [min {}]", runs.rel_stddev(), range)
+        }
+        // Like perf, only show how long the counters were
+        // actually running when the kernel had to multiplex them.
+        None if count.time_running < count.time_enabled => {
+            format!("  ({:.2}%)", count.running())
+        }
+        None => String::new(),
+    };
+    let mut line = match count.unit() {
+        ("", _) => format!(" {}Number of {}: {:.0}{}", prefix, event, value, suffix),
+        (unit, _) => format!(" {}{:.2} {} {}{}", prefix, value, unit, event, suffix),
+    };
+    if let Some((metric, unit)) = &count.metric {
+        line += &format!("\n {}{}: {:.3}", prefix, unit, metric);
+    }
+    line
+}
+
+/// The fields of `perf stat -x`: value, unit, event, variance (with
+/// `-r`), run time, percentage running, metric value and metric unit,
+/// after the interval timestamp and CPU or thread, if there are any.
+fn csv(count: &Count, sep: &str) -> String {
+    let mut fields = csv_prefix(count.interval, &count.label);
+    fields.push(match (&count.value, count.value()) {
+        (Value::NotSupported, _) => "<not supported>".to_string(),
+        (_, None) => "<not counted>".to_string(),
+        (_, Some(value)) if count.unit().0.is_empty() => format!("{:.0}", value),
+        (_, Some(value)) => format!("{:.2}", value),
+    });
+    fields.push(count.unit().0.to_string());
+    fields.push(count.event.to_string());
+    if let Some(runs) = count.runs {
+        fields.push(format!("{:.2}%", runs.rel_stddev()));
+    }
+    fields.push(count.time_running.to_string());
+    fields.push(format!("{:.2}", count.running()));
+    match &count.metric {
+        Some((value, unit)) => {
+            fields.push(format!("{:.3}", value));
+            fields.push(unit.clone());
+        }
+        None => fields.extend(vec![String::new(), String::new()]),
+    }
+    fields.join(sep)
+}
+
+/// The interval timestamp and label fields a line starts with.
+fn csv_prefix(interval: Option<f64>, label: &Option<Label>) -> Vec<String> {
+    let mut fields = Vec::new();
+    if let Some(interval) = interval {
+        fields.push(format!("{:.9}", interval));
+    }
+    match label {
+        Some(Label::Cpu(cpu)) => fields.push(format!("CPU{}", cpu)),
+        Some(Label::Thread { comm, tid }) => fields.push(format!("{}-{}", comm, tid)),
+        None => {}
+    }
+    fields
+}
+
+/// The `cpu`, `thread` and `tid` fields of a JSON object.
+fn json_label<'a>(label: &Option<Label<'a>>) -> (Option<i32>, Option<&'a str>, Option<i32>) {
+    match label {
+        Some(Label::Cpu(cpu)) => (Some(*cpu), None, None),
+        Some(Label::Thread { comm, tid }) => (None, Some(*comm), Some(*tid)),
+        None => (None, None, None),
+    }
+}
+
+fn json(count: &Count) -> String {
+    let (_, scale) = count.unit();
+    let (cpu, thread, tid) = json_label(&count.label);
+    let runs = count.runs.filter(|_| count.value().is_some());
+    let object = JsonCount {
+        version: JSON_VERSION,
+        interval: count.interval,
+        cpu,
+        thread,
+        tid,
+        event: count.event.to_string(),
+        counter_value: count.value(),
+        supported: !matches!(count.value, Value::NotSupported),
+        unit: count.unit().0,
+        time_enabled: count.time_enabled,
+        time_running: count.time_running,
+        pcnt_running: count.running(),
+        variance: runs.map(|r| r.variance() / (scale * scale)),
+        rel_stddev: runs.map(Stats::rel_stddev),
+        min: runs.and_then(Stats::min).map(|v| v as f64 / scale),
+        max: runs.and_then(Stats::max).map(|v| v as f64 / scale),
+        runs: runs.map(|r| r.values.iter().map(|v| *v as f64 / scale).collect()),
+        metric_value: count.metric.as_ref().map(|(value, _)| *value),
+        metric_unit: count.metric.as_ref().map(|(_, unit)| unit.as_str()),
+    };
+    serde_json::to_string(&object).unwrap()
+}
+
+/// The topdown tree, under a heading, with each level indented more.
+fn text_shares(shares: &[Share], interval: Option<f64>, label: &Option<Label>) -> Vec<String> {
+    let prefix = prefix(interval, label);
+    let mut lines = vec![format!("\n {}Topdown, % of pipeline slots:", prefix)];
+    for share in shares {
+        lines.push(format!(
+            " {}{}{:5.1}%  {}",
+            prefix,
+            "   ".repeat(share.level),
+            share.percent,
+            share.description
+        ));
+    }
+    lines
+}
+
+/// A topdown area's share as `-x` separated fields: percentage, `%`
+/// and the area, after the interval timestamp and CPU or thread.
+fn csv_share(share: &Share, interval: Option<f64>, label: &Option<Label>, sep: &str) -> String {
+    let mut fields = csv_prefix(interval, label);
+    fields.push(format!("{:.1}", share.percent));
+    fields.push("%".to_string());
+    fields.push(share.name.to_string());
+    fields.join(sep)
+}
+
+fn json_share(share: &Share, interval: Option<f64>, label: &Option<Label>) -> String {
+    let (cpu, thread, tid) = json_label(label);
+    let object = JsonShare {
+        version: JSON_VERSION,
+        interval,
+        cpu,
+        thread,
+        tid,
+        topdown: share.name,
+        level: share.level,
+        percent: share.percent,
+    };
+    serde_json::to_string(&object).unwrap()
+}
+
+#[cfg(test)]
+#[test]
+fn formats_test() {
+    let cycles = EventSpec::from(crate::stat::StatEvent::Cycles);
+    let clock = EventSpec::from(crate::stat::StatEvent::TaskClock);
+    let count = Count {
+        event: &cycles,
+        interval: None,
+        label: Some(Label::Cpu(2)),
+        value: Value::Counted(1234),
+        time_enabled: 200,
+        time_running: 100,
+        runs: None,
+        metric: None,
+    };
+    assert_eq!(text(&count), " CPU2 Number of cycles: 1234  (50.00%)");
+    assert_eq!(csv(&count, ";"), "CPU2;1234;;cycles;100;50.00;;");
+    let object: serde_json::Value = serde_json::from_str(&json(&count)).unwrap();
+    assert_eq!(object["version"], JSON_VERSION);
+    assert_eq!(object["cpu"], 2);
+    assert_eq!(object["counter-value"], 1234.0);
+    assert_eq!(object["pcnt-running"], 50.0);
+    assert!(object.get("runs").is_none());
+
+    let mut runs = Stats::default();
+    runs.push(1_000_000);
+    runs.push(3_000_000);
+    let count = Count {
+        event: &clock,
+        interval: Some(1.5),
+        label: None,
+        value: Value::Counted(2_000_000),
+        time_enabled: 10,
+        time_running: 10,
+        runs: Some(&runs),
+        metric: Some((0.5, "CPU utilized".to_string())),
+    };
+    assert_eq!(
+        text(&count),
+        "    1.500000000 2.00 msec task clock  ( +- 50.00% )  [min 1.00, max 3.00]\n    \
+         1.500000000 CPU utilized: 0.500"
+    );
+    assert_eq!(
+        csv(&count, ","),
+        "1.500000000,2.00,msec,task-clock,50.00%,10,100.00,0.500,CPU utilized"
+    );
+    let object: serde_json::Value = serde_json::from_str(&json(&count)).unwrap();
+    assert_eq!(object["event"], "task-clock");
+    assert_eq!(object["counter-value"], 2.0);
+    assert_eq!(object["unit"], "msec");
+    assert_eq!(object["variance"], 2.0);
+    assert_eq!(object["runs"], serde_json::json!([1.0, 3.0]));
+    assert_eq!(object["metric-unit"], "CPU utilized");
+
+    let count = Count {
+        value: Value::NotSupported,
+        runs: None,
+        metric: None,
+        ..count
+    };
+    assert_eq!(
+        csv(&count, ","),
+        "1.500000000,<not supported>,msec,task-clock,10,100.00,,"
+    );
+    let object: serde_json::Value = serde_json::from_str(&json(&count)).unwrap();
+    assert_eq!(object["supported"], false);
+    assert!(object["counter-value"].is_null());
+}
+
+#[test]
+fn shares_test() {
+    let shares = [
+        Share {
+            name: "tma_retiring",
+            description: "Retiring",
+            level: 1,
+            percent: 42.31,
+        },
+        Share {
+            name: "tma_heavy_operations",
+            description: "Heavy operations",
+            level: 2,
+            percent: 5.0,
+        },
+    ];
+    assert_eq!(
+        text_shares(&shares, None, &Some(Label::Cpu(1))),
+        vec![
+            "\n CPU1 Topdown, % of pipeline slots:",
+            " CPU1     42.3%  Retiring",
+            " CPU1         5.0%  Heavy operations"
+        ]
+    );
+    assert_eq!(
+        csv_share(&shares[1], Some(2.0), &None, ","),
+        "2.000000000,5.0,%,tma_heavy_operations"
+    );
+    let object: serde_json::Value =
+        serde_json::from_str(&json_share(&shares[0], None, &None)).unwrap();
+    assert_eq!(object["topdown"], "tma_retiring");
+    assert_eq!(object["level"], 1);
+    assert_eq!(object["percent"], 42.31);
+    assert!(object.get("event").is_none());
+}
@@ -0,0 +1,69 @@
+//! Statistics over repeated runs of a command, for `ruperf stat -r`.
+
+/// One count, as measured on each run.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// The count from each run, in the order they ran.
+    pub values: Vec<u64>,
+}
+
+impl Stats {
+    pub fn push(&mut self, value: u64) {
+        self.values.push(value);
+    }
+    pub fn mean(&self) -> f64 {
+        if self.values.is_empty() {
+            return 0.0;
+        }
+        self.values.iter().map(|v| *v as f64).sum::<f64>() / self.values.len() as f64
+    }
+    /// The sample variance, or 0 with fewer than two runs.
+    pub fn variance(&self) -> f64 {
+        let n = self.values.len();
+        if n < 2 {
+            return 0.0;
+        }
+        let mean = self.mean();
+        let squares: f64 = self.values.iter().map(|v| (*v as f64 - mean).powi(2)).sum();
+        squares / (n - 1) as f64
+    }
+    /// The standard deviation of the mean as a percentage of
+    /// it, which is what perf shows as `+- x.xx%`.
+    pub fn rel_stddev(&self) -> f64 {
+        let mean = self.mean();
+        if mean == 0.0 {
+            return 0.0;
+        }
+        let stddev_mean = (self.variance() / self.values.len() as f64).sqrt();
+        100.0 * stddev_mean / mean
+    }
+    pub fn min(&self) -> Option<u64> {
+        self.values.iter().copied().min()
+    }
+    pub fn max(&self) -> Option<u64> {
+        self.values.iter().copied().max()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn stats_test() {
+    let mut stats = Stats::default();
+    assert_eq!(stats.mean(), 0.0);
+    assert_eq!(stats.rel_stddev(), 0.0);
+    assert_eq!(stats.min(), None);
+    for v in &[10, 12, 14, 16] {
+        stats.push(*v);
+    }
+    assert_eq!(stats.mean(), 13.0);
+    assert!((stats.variance() - 20.0 / 3.0).abs() < 1e-9);
+    // stddev / sqrt(4) / 13
+    let expected = 100.0 * (20.0_f64 / 3.0).sqrt() / 2.0 / 13.0;
+    assert!((stats.rel_stddev() - expected).abs() < 1e-9);
+    assert_eq!((stats.min(), stats.max()), (Some(10), Some(16)));
+
+    let mut one = Stats::default();
+    one.push(7);
+    assert_eq!(one.variance(), 0.0);
+    assert_eq!(one.rel_stddev(), 0.0);
+}
@@ -0,0 +1,340 @@
+//! Top-down microarchitecture analysis, for `--topdown`: how the
+//! CPU's pipeline slots were spent, split at level 1 into retiring,
+//! bad speculation, frontend bound and backend bound, and each
+//! of those split in two at level 2.
+//!
+//! Intel CPUs since Ice Lake count each area's share of the slots
+//! directly, as the `slots` and `topdown-*` events of the `cpu`
+//! PMU, which must be counted in a group led by `slots`. Older
+//! ones have `topdown-total-slots` and friends instead, which
+//! level 1 is worked out from with the method's generic formula.
+
+use super::metric::{Counts, Expr};
+use super::output::Share;
+use crate::event::open::{EventSpec, Pmu};
+
+/// An area the slots are split into.
+struct Area {
+    /// What machine readable output calls it, as perf's metrics do.
+    name: &'static str,
+    description: &'static str,
+    /// How deep in the tree it is, 1 or 2.
+    level: usize,
+    /// Its share of the slots.
+    expr: &'static str,
+}
+
+/// The areas, in the order they're printed, on CPUs with perf
+/// metrics, where each `topdown-*` event counts its share of `slots`.
+const PERF_METRICS: &[Area] = &[
+    Area {
+        name: "tma_retiring",
+        description: "Retiring",
+        level: 1,
+        expr: "topdown-retiring / slots",
+    },
+    Area {
+        name: "tma_light_operations",
+        description: "Light operations",
+        level: 2,
+        expr: "(topdown-retiring - topdown-heavy-ops) / slots",
+    },
+    Area {
+        name: "tma_heavy_operations",
+        description: "Heavy operations",
+        level: 2,
+        expr: "topdown-heavy-ops / slots",
+    },
+    Area {
+        name: "tma_bad_speculation",
+        description: "Bad speculation",
+        level: 1,
+        expr: "topdown-bad-spec / slots",
+    },
+    Area {
+        name: "tma_branch_mispredicts",
+        description: "Branch mispredicts",
+        level: 2,
+        expr: "topdown-br-mispredict / slots",
+    },
+    Area {
+        name: "tma_machine_clears",
+        description: "Machine clears",
+        level: 2,
+        expr: "(topdown-bad-spec - topdown-br-mispredict) / slots",
+    },
+    Area {
+        name: "tma_frontend_bound",
+        description: "Frontend bound",
+        level: 1,
+        expr: "topdown-fe-bound / slots",
+    },
+    Area {
+        name: "tma_fetch_latency",
+        description: "Fetch latency",
+        level: 2,
+        expr: "topdown-fetch-lat / slots",
+    },
+    Area {
+        name: "tma_fetch_bandwidth",
+        description: "Fetch bandwidth",
+        level: 2,
+        expr: "(topdown-fe-bound - topdown-fetch-lat) / slots",
+    },
+    Area {
+        name: "tma_backend_bound",
+        description: "Backend bound",
+        level: 1,
+        expr: "topdown-be-bound / slots",
+    },
+    Area {
+        name: "tma_memory_bound",
+        description: "Memory bound",
+        level: 2,
+        expr: "topdown-mem-bound / slots",
+    },
+    Area {
+        name: "tma_core_bound",
+        description: "Core bound",
+        level: 2,
+        expr: "(topdown-be-bound - topdown-mem-bound) / slots",
+    },
+];
+
+/// Level 1 on older CPUs, from the generic formula. Backend
+/// bound is whatever's left of the slots.
+const GENERIC: &[Area] = &[
+    Area {
+        name: "tma_retiring",
+        description: "Retiring",
+        level: 1,
+        expr: "topdown-slots-retired / topdown-total-slots",
+    },
+    Area {
+        name: "tma_bad_speculation",
+        description: "Bad speculation",
+        level: 1,
+        expr: "(topdown-slots-issued - topdown-slots-retired + topdown-recovery-bubbles) \
+               / topdown-total-slots",
+    },
+    Area {
+        name: "tma_frontend_bound",
+        description: "Frontend bound",
+        level: 1,
+        expr: "topdown-fetch-bubbles / topdown-total-slots",
+    },
+    Area {
+        name: "tma_backend_bound",
+        description: "Backend bound",
+        level: 1,
+        expr: "1 - (topdown-fetch-bubbles + topdown-slots-issued + topdown-recovery-bubbles) \
+               / topdown-total-slots",
+    },
+];
+
+/// The areas shown, and how to work them out from the counts.
+pub struct Topdown {
+    areas: Vec<(&'static Area, Expr)>,
+    /// What the counts of the events are multiplied by, if not 1.
+    scales: Vec<(String, f64)>,
+}
+
+impl Topdown {
+    /// The areas down to `level` that the events of the `cpu` PMU
+    /// can work out, and the group of events to count for them,
+    /// or why they can't be.
+    pub fn new(cpu: Option<&Pmu>, level: usize) -> Result<(Self, Vec<EventSpec>), String> {
+        let has = |event: &str| matches!(cpu, Some(cpu) if cpu.events.contains_key(event));
+        let (table, leader) = if has("slots") {
+            (PERF_METRICS, "slots")
+        } else if has("topdown-total-slots") {
+            (GENERIC, "topdown-total-slots")
+        } else {
+            return Err("--topdown needs the slots and topdown events of Intel \
+                        CPUs, which this CPU's PMU doesn't have"
+                .to_string());
+        };
+        let deepest = table.iter().map(|area| area.level).max().unwrap_or(1);
+        if level > deepest {
+            return Err(format!(
+                "this CPU's topdown events only go down to level {}",
+                deepest
+            ));
+        }
+        let areas: Vec<(&Area, Expr)> = table
+            .iter()
+            .filter(|area| area.level <= level)
+            .map(|area| (area, area.expr.parse().unwrap()))
+            .collect();
+        let mut names: Vec<&str> = Vec::new();
+        for (_, expr) in &areas {
+            for event in expr.events() {
+                if !names.contains(&event) {
+                    names.push(event);
+                }
+            }
+        }
+        // The group has to be led by the slots.
+        names.sort_by_key(|name| *name != leader);
+        let missing: Vec<&str> = names.iter().copied().filter(|name| !has(name)).collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "topdown level {} needs the {} events, which this CPU's PMU doesn't have",
+                level,
+                missing.join(", ")
+            ));
+        }
+        let cpu = cpu.unwrap();
+        let events = names
+            .iter()
+            .map(|name| cpu.event(name).unwrap().name(name))
+            .collect();
+        let scales = names
+            .iter()
+            .filter_map(|name| Some((name.to_string(), *cpu.scales.get(*name)?)))
+            .collect();
+        Ok((Topdown { areas, scales }, events))
+    }
+    /// Each area's share of the slots, as a percentage, from
+    /// `counts`, or `None` if level 1 can't be worked out.
+    pub fn shares(&self, counts: &Counts) -> Option<Vec<Share>> {
+        let count = |name: &str| {
+            let scale = self.scales.iter().find(|(event, _)| event == name);
+            Some(counts.get(name)? * scale.map_or(1.0, |(_, scale)| *scale))
+        };
+        let mut shares = Vec::new();
+        for (area, expr) in &self.areas {
+            match expr.eval(&count) {
+                Some(share) => shares.push(Share {
+                    name: area.name,
+                    description: area.description,
+                    level: area.level,
+                    percent: 100.0 * share,
+                }),
+                None if area.level == 1 => return None,
+                None => {}
+            }
+        }
+        Some(shares)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn generic_test() {
+    use crate::event::open::fixture;
+    let cpu = Pmu::open(&fixture("sysfs/bus/event_source/devices"), "cpu").unwrap();
+    let (topdown, events) = Topdown::new(Some(&cpu), 1).unwrap();
+    let names: Vec<&str> = events.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(
+        names,
+        vec![
+            "topdown-total-slots",
+            "topdown-slots-retired",
+            "topdown-slots-issued",
+            "topdown-recovery-bubbles",
+            "topdown-fetch-bubbles"
+        ]
+    );
+    assert_eq!(events[1].config, 0x02c2);
+    let err = Topdown::new(Some(&cpu), 2).err().unwrap();
+    assert_eq!(err, "this CPU's topdown events only go down to level 1");
+
+    // Total and recovery bubbles are scaled by 2.
+    let mut counts = Counts::new(1e9);
+    for (event, count) in events.iter().zip(&[500.0, 400.0, 600.0, 25.0, 150.0]) {
+        counts.add(event, *count);
+    }
+    let shares = topdown.shares(&counts).unwrap();
+    let percents: Vec<(&str, f64)> = shares.iter().map(|s| (s.name, s.percent.round())).collect();
+    assert_eq!(
+        percents,
+        vec![
+            ("tma_retiring", 40.0),
+            ("tma_bad_speculation", 25.0),
+            ("tma_frontend_bound", 15.0),
+            ("tma_backend_bound", 20.0)
+        ]
+    );
+    assert!(topdown.shares(&Counts::new(1e9)).is_none());
+}
+
+#[test]
+fn perf_metrics_test() {
+    use crate::event::open::fixture;
+    let mut cpu = Pmu::open(&fixture("sysfs/bus/event_source/devices"), "cpu").unwrap();
+    for (i, event) in [
+        "slots",
+        "topdown-retiring",
+        "topdown-bad-spec",
+        "topdown-fe-bound",
+        "topdown-be-bound",
+    ]
+    .iter()
+    .enumerate()
+    {
+        let umask = if i == 0 { 4 } else { 0x80 + i - 1 };
+        let terms = format!("event=0x00,umask={:#x}", umask);
+        cpu.events.insert(event.to_string(), terms);
+    }
+    // Ice Lake has level 1 only.
+    let err = Topdown::new(Some(&cpu), 2).err().unwrap();
+    assert_eq!(
+        err,
+        "topdown level 2 needs the topdown-heavy-ops, topdown-br-mispredict, \
+         topdown-fetch-lat, topdown-mem-bound events, which this CPU's PMU doesn't have"
+    );
+    for (event, umask) in &[
+        ("topdown-heavy-ops", 0x84),
+        ("topdown-br-mispredict", 0x85),
+        ("topdown-fetch-lat", 0x86),
+        ("topdown-mem-bound", 0x87),
+    ] {
+        let terms = format!("event=0x00,umask={:#x}", umask);
+        cpu.events.insert(event.to_string(), terms);
+    }
+    let (topdown, events) = Topdown::new(Some(&cpu), 2).unwrap();
+    assert_eq!(events.len(), 9);
+    assert_eq!(
+        (events[0].name.as_str(), events[0].config),
+        ("slots", 0x400)
+    );
+
+    let mut counts = Counts::new(1e9);
+    for (name, count) in &[
+        ("slots", 1000.0),
+        ("topdown-retiring", 500.0),
+        ("topdown-heavy-ops", 100.0),
+        ("topdown-bad-spec", 100.0),
+        ("topdown-br-mispredict", 80.0),
+        ("topdown-fe-bound", 150.0),
+        ("topdown-be-bound", 250.0),
+        ("topdown-mem-bound", 200.0),
+    ] {
+        counts.add(events.iter().find(|e| e.name == *name).unwrap(), *count);
+    }
+    let shares = topdown.shares(&counts).unwrap();
+    let percents: Vec<(&str, usize, f64)> = shares
+        .iter()
+        .map(|s| (s.name, s.level, s.percent.round()))
+        .collect();
+    // Fetch latency wasn't counted, so it and fetch bandwidth are left out.
+    assert_eq!(
+        percents,
+        vec![
+            ("tma_retiring", 1, 50.0),
+            ("tma_light_operations", 2, 40.0),
+            ("tma_heavy_operations", 2, 10.0),
+            ("tma_bad_speculation", 1, 10.0),
+            ("tma_branch_mispredicts", 2, 8.0),
+            ("tma_machine_clears", 2, 2.0),
+            ("tma_frontend_bound", 1, 15.0),
+            ("tma_backend_bound", 1, 25.0),
+            ("tma_memory_bound", 2, 20.0),
+            ("tma_core_bound", 2, 5.0)
+        ]
+    );
+
+    let err = Topdown::new(None, 1).err().unwrap();
+    assert!(err.starts_with("--topdown needs the slots and topdown events"));
+}
@@ -1,7 +1,7 @@
 use std::io::Read;
 use std::io::Write;
 
-use crate::event::open::Event;
+use crate::event::open::{Event, Reading};
 use crate::stat::StatEvent;
 use crate::test::RunSettings;
 use crate::test::Test;
@@ -66,9 +66,18 @@ pub fn test_counts() -> Test {
             child_reader,
             child_writer,
         );
-        let start: isize;
-        let stop: isize;
-        let event = Event::new(event_to_run, Some(pid_child));
+        let start: Reading;
+        let stop: Reading;
+        let event = match Event::new(event_to_run, Some(pid_child)) {
+            Ok(event) => event,
+            Err(e) => {
+                unsafe {
+                    libc::kill(pid_child, libc::SIGKILL);
+                    libc::waitpid(pid_child, std::ptr::null_mut(), 0);
+                }
+                return fail(format!("\nINFO:\t{}", e), settings);
+            }
+        };
         let mut buf = [0];
         let nread = parent_reader.read(&mut buf).unwrap();
         if nread != 1 {
@@ -98,7 +107,7 @@ pub fn test_counts() -> Test {
             );
         }
         stop = event.stop_counter().unwrap();
-        let count = stop - start;
+        let count = stop.since(&start).value() as isize;
         if count < sane_number {
             return fail(
                 format!(
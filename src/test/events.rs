@@ -34,11 +34,14 @@ fn event_sanity_check(event: StatEvent, settings: &RunSettings) -> TestResult {
         TestResult::Failed("(1)".to_string())
     }
 
-    let event = Event::new(event, None);
-    let begin_count: isize = event.start_counter().unwrap();
+    let event = match Event::new(event, None) {
+        Ok(event) => event,
+        Err(e) => return fail(format!("\nINFO:\t{}", e), settings),
+    };
+    let begin_count = event.start_counter().unwrap().value();
     useless_stuff();
-    let end_count = event.stop_counter().unwrap();
-    if begin_count <= 0 || end_count <= 0 {
+    let end_count = event.stop_counter().unwrap().value();
+    if begin_count == 0 || end_count == 0 {
         return fail(
             "\nINFO:\t
                 The value recieved from start / stop counter was 0."
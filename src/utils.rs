@@ -1,4 +1,5 @@
 //! Errors for `ruperf stat`.
+use crate::event::open::SyntaxErr;
 use thiserror::Error;
 
 /// Parse errors for CLI
@@ -6,4 +7,12 @@ use thiserror::Error;
 pub enum ParseError {
     #[error("Invalid Event")]
     InvalidEvent,
+    /// Shows the event string with a caret
+    /// under the column the error points at.
+    #[error("{err}\n\n    {input}\n    {}^", " ".repeat(.err.column - 1))]
+    Syntax { input: String, err: SyntaxErr },
+    #[error("Invalid CPU list '{0}', expected e.g. 0-3,8")]
+    CpuList(String),
+    #[error("Invalid duration '{0}', expected e.g. 5s, 500ms or 2m")]
+    Duration(String),
 }
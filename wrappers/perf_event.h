@@ -1,3 +1,4 @@
 //! `bindgen` will generate Rust bindings from
 //! this header. The bindings are in: `/src/bindings/perf_event.rs`.
 #include <linux/perf_event.h>
+#include <linux/hw_breakpoint.h>